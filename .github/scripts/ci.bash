@@ -60,6 +60,7 @@ run_check() {
 
 run_test() {
     cargo test -p utils --target "x86_64-unknown-linux-gnu"
+    cargo test -p utils --target "x86_64-unknown-linux-gnu" --features fec
 }
 
 run_build() {
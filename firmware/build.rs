@@ -0,0 +1,63 @@
+//! Expose a hash of the firmware build, so that both halves can check that
+//! they run the same firmware
+
+use std::path::Path;
+use std::process::Command;
+
+/// Cargo features changing what goes on the wire between the halves
+const WIRE_FEATURES: [&str; 2] = ["FEC", "HALF_DUPLEX"];
+
+/// Run git with the given arguments and return its output
+fn git(args: &[&str]) -> Option<Vec<u8>> {
+    let output = Command::new("git").args(args).output().ok()?;
+    output.status.success().then_some(output.stdout)
+}
+
+fn main() {
+    let head = git(&["rev-parse", "HEAD"])
+        .and_then(|out| String::from_utf8(out).ok())
+        .and_then(|sha| u32::from_str_radix(sha.get(..8)?, 16).ok())
+        .unwrap_or(0);
+    // Uncommitted changes get a hash of their own (FNV-1a of the diff)
+    let diff = git(&["diff", "HEAD"]).unwrap_or_default();
+    let dirty = if diff.is_empty() {
+        0
+    } else {
+        diff.iter().fold(0x811c_9dc5_u32, |hash, byte| {
+            (hash ^ *byte as u32).wrapping_mul(0x0100_0193)
+        })
+    };
+    // Halves built with different wire features may not understand each
+    // other, so they must not be found compatible
+    let features = WIRE_FEATURES
+        .iter()
+        .filter(|feature| std::env::var_os(format!("CARGO_FEATURE_{}", feature)).is_some())
+        .flat_map(|feature| feature.bytes().chain([0]))
+        .fold(0x811c_9dc5_u32, |hash, byte| {
+            (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+        });
+    println!("cargo:rustc-env=BUILD_HASH={:08x}", head ^ dirty ^ features);
+    // A commit moves the branch HEAD points to, not HEAD itself
+    let mut git_files = vec![
+        "HEAD".to_string(),
+        "index".to_string(),
+        "packed-refs".to_string(),
+    ];
+    if let Some(branch) =
+        git(&["symbolic-ref", "-q", "HEAD"]).and_then(|out| String::from_utf8(out).ok())
+    {
+        git_files.push(branch.trim().to_string());
+    }
+    for file in git_files {
+        if let Some(path) =
+            git(&["rev-parse", "--git-path", &file]).and_then(|out| String::from_utf8(out).ok())
+        {
+            // Watching a missing file would rerun this script on every build
+            if Path::new(path.trim()).exists() {
+                println!("cargo:rerun-if-changed={}", path.trim());
+            }
+        }
+    }
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=../utils/src");
+}
@@ -20,7 +20,8 @@ use {defmt_rtt as _, panic_probe as _};
 bind_interrupts!(struct PioIrq1 {
     PIO1_IRQ_0 => PioInterruptHandler<PIO1>;
 });
-const USART_SPEED: u64 = 57600;
+/// Speed of the link, in bauds
+const USART_SPEED: u32 = 57600;
 const TX: usize = 0;
 const RX: usize = 1;
 pub type SmTx<'a> = StateMachine<'a, PIO1, { TX }>;
@@ -94,6 +95,7 @@ pub async fn full_duplex_comm<'a>(
     gpio_pin_1: PIN_1,
     gpio_pin_29: PIN_29,
     status_led: &mut Output<'a>,
+    speed: u32,
 ) {
     let (mut pin_tx, mut pin_rx) = if false {
         (
@@ -107,20 +109,22 @@ pub async fn full_duplex_comm<'a>(
         )
     };
 
-    let tx_sm = task_tx(&mut pio_common, sm0, &mut pin_tx);
-    let rx_sm = task_rx(&mut pio_common, sm1, &mut pin_rx);
+    let tx_sm = task_tx(&mut pio_common, sm0, &mut pin_tx, speed);
+    let rx_sm = task_rx(&mut pio_common, sm1, &mut pin_rx, speed);
 
     futures::future::join(tx_loop(tx_sm), rx_loop(rx_sm, status_led)).await;
 }
 
-fn pio_freq() -> fixed::FixedU32<fixed::types::extra::U8> {
-    (U56F8::from_num(clk_sys_freq()) / (8 * USART_SPEED)).to_fixed()
+/// Clock divider of the state machines for the given speed, in bauds
+fn pio_freq(speed: u32) -> fixed::FixedU32<fixed::types::extra::U8> {
+    (U56F8::from_num(clk_sys_freq()) / (8 * speed as u64)).to_fixed()
 }
 
 fn task_tx<'a>(
     common: &mut PioCommon<'a>,
     mut sm_tx: SmTx<'a>,
     tx_pin: &mut PioPin<'a>,
+    speed: u32,
 ) -> SmTx<'a> {
     let tx_prog = pio_proc::pio_file!("src/tx.pio");
     sm_tx.set_pins(Level::High, &[tx_pin]);
@@ -134,7 +138,7 @@ fn task_tx<'a>(
     cfg.shift_out.direction = ShiftDirection::Right;
     cfg.shift_out.threshold = 32;
     cfg.fifo_join = FifoJoin::TxOnly;
-    cfg.clock_divider = pio_freq();
+    cfg.clock_divider = pio_freq(speed);
     sm_tx.set_config(&cfg);
     sm_tx.set_enable(true);
 
@@ -145,6 +149,7 @@ fn task_rx<'a>(
     common: &mut PioCommon<'a>,
     mut sm_rx: SmRx<'a>,
     rx_pin: &mut PioPin<'a>,
+    speed: u32,
 ) -> SmRx<'a> {
     let rx_prog = pio_proc::pio_file!("src/rx.pio");
     sm_rx.set_pins(Level::High, &[rx_pin]);
@@ -158,7 +163,7 @@ fn task_rx<'a>(
     cfg.shift_in.direction = ShiftDirection::Right;
     cfg.shift_in.threshold = 32;
     cfg.fifo_join = FifoJoin::RxOnly;
-    cfg.clock_divider = pio_freq();
+    cfg.clock_divider = pio_freq(speed);
     sm_rx.set_config(&cfg);
     sm_rx.set_enable(true);
 
@@ -181,6 +186,7 @@ async fn main(_spawner: Spawner) {
         p.PIN_1,
         p.PIN_29,
         &mut status_led,
+        USART_SPEED,
     )
     .await;
 }
@@ -1,29 +1,31 @@
 use crate::hid::{KeyboardReport, HID_KB_CHANNEL};
 use crate::mouse::MouseHandler;
-use crate::pmw3360::{SensorCommand, SENSOR_CMD_CHANNEL};
+use crate::pmw3360::SensorCommand;
 use crate::rgb_leds::{AnimCommand, ANIM_CHANNEL};
-use crate::side::SIDE_CHANNEL;
+use crate::side::{SideCommand, SIDE_CHANNEL, SIDE_CMD_CHANNEL};
 use embassy_futures::select::{select, Either};
 use embassy_rp::peripherals::USB;
 use embassy_rp::usb::Driver;
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
-use embassy_time::{Duration, Ticker};
+use embassy_time::{Duration, Instant, Ticker};
 use embassy_usb::class::hid::HidWriter;
+use keyberon::action::Action;
 use keyberon::key_code::KeyCode;
 use keyberon::layout::{CustomEvent as KbCustomEvent, Event as KBEvent, Layout};
+use utils::auto_mouse::{AutoMouse, AutoMouseConfig, Transition};
 use utils::serde::Event;
 
 /// Basic layout for the keyboard
 #[cfg(feature = "keymap_basic")]
-use crate::keymap_basic::{KBLayout, LAYERS};
+use crate::keymap_basic::{KBLayout, AUTO_MOUSE_KEY, LAYERS};
 
 /// Keymap by Boris Faure
 #[cfg(feature = "keymap_borisfaure")]
-use crate::keymap_borisfaure::{KBLayout, LAYERS};
+use crate::keymap_borisfaure::{KBLayout, AUTO_MOUSE_KEY, LAYERS};
 
 /// Test layout for the keyboard
 #[cfg(feature = "keymap_test")]
-use crate::keymap_test::{KBLayout, LAYERS};
+use crate::keymap_test::{KBLayout, AUTO_MOUSE_KEY, LAYERS};
 
 /// Layout refresh rate, in ms
 const REFRESH_RATE_MS: u64 = 1;
@@ -48,12 +50,57 @@ pub enum CustomEvent {
     IncreaseCpi,
     /// Decrease sensor CPI
     DecreaseCpi,
+    /// Switch to the next sensor CPI of the normal set
+    NextCpi,
+    /// Switch to the previous sensor CPI of the normal set
+    PreviousCpi,
+    /// Switch to the next sensor CPI of the sniping set
+    NextSnipingCpi,
+    /// Switch to the previous sensor CPI of the sniping set
+    PreviousSnipingCpi,
+    /// Sniping: low sensor CPI for precise pointing, while held
+    Sniping,
+    /// Switch to the next pointer acceleration profile
+    NextAccelProfile,
+    /// Switch to the previous pointer acceleration profile
+    PreviousAccelProfile,
     /// Next Animation of the RGB LEDs
     NextLedAnimation,
     /// Reset to usb mass storage
     ResetToUsbMassStorage,
+    /// Log the statistics of the protocol between the halves
+    DumpProtocolStats,
 }
 
+impl CustomEvent {
+    /// Whether the event is a mouse one, keeping the automatic mouse layer on
+    fn is_mouse(&self) -> bool {
+        matches!(
+            self,
+            CustomEvent::MouseLeftClick
+                | CustomEvent::MouseRightClick
+                | CustomEvent::MouseWheelClick
+                | CustomEvent::BallIsWheel
+                | CustomEvent::IncreaseCpi
+                | CustomEvent::DecreaseCpi
+                | CustomEvent::NextCpi
+                | CustomEvent::PreviousCpi
+                | CustomEvent::NextSnipingCpi
+                | CustomEvent::PreviousSnipingCpi
+                | CustomEvent::Sniping
+                | CustomEvent::NextAccelProfile
+                | CustomEvent::PreviousAccelProfile
+        )
+    }
+}
+
+/// Automatic mouse layer: the ball has to travel 10 counts to turn it on, it
+/// is turned off after 650ms without motion
+const AUTO_MOUSE_CONFIG: AutoMouseConfig = AutoMouseConfig {
+    threshold: 10,
+    timeout_ms: 650,
+};
+
 /// Debug tick counter: every 5s
 #[cfg(feature = "debug_tick")]
 const TICK_DEBUG: usize = 5000;
@@ -70,6 +117,8 @@ pub struct Core<'a> {
     kb_report: KeyboardReport,
     /// Mouse handler
     mouse: MouseHandler,
+    /// Automatic mouse layer
+    auto_mouse: AutoMouse,
     /// HID mouse writer
     hid_mouse_writer: HidWriter<'a, Driver<'a, USB>, 7>,
     /// Debug tick counter
@@ -89,6 +138,7 @@ impl<'a> Core<'a> {
             current_layer: 0,
             kb_report: KeyboardReport::default(),
             mouse: MouseHandler::new(),
+            auto_mouse: AutoMouse::new(AUTO_MOUSE_CONFIG),
             hid_mouse_writer,
             #[cfg(feature = "debug_tick")]
             debug_tick: TICK_DEBUG,
@@ -112,9 +162,40 @@ impl<'a> Core<'a> {
     /// On key event
     fn on_key_event(&mut self, event: KBEvent) {
         defmt::info!("Event: {:?}", defmt::Debug2Format(&event));
+        // Turn the automatic mouse layer off before the key is looked up in
+        // the layout, for it to be found on the layer below
+        let (r, c) = event.coord();
+        let is_mouse_key = matches!(
+            LAYERS[self.layout.current_layer()]
+                .get(r as usize)
+                .and_then(|row| row.get(c as usize)),
+            Some(Action::Custom(e)) if e.is_mouse()
+        );
+        let transition =
+            self.auto_mouse
+                .on_key(is_mouse_key, event.is_press(), Instant::now().as_millis());
+        self.apply_auto_mouse(transition);
         self.layout.event(event);
     }
 
+    /// Press or release the virtual key of the automatic mouse layer
+    fn apply_auto_mouse(&mut self, transition: Transition) {
+        let Some((r, c)) = AUTO_MOUSE_KEY else {
+            return;
+        };
+        match transition {
+            Transition::None => {}
+            Transition::Activate => {
+                defmt::info!("Automatic mouse layer on");
+                self.layout.event(KBEvent::Press(r, c));
+            }
+            Transition::Deactivate => {
+                defmt::info!("Automatic mouse layer off");
+                self.layout.event(KBEvent::Release(r, c));
+            }
+        }
+    }
+
     /// Process the state of the keyboard and mouse
     async fn tick(&mut self) {
         #[cfg(feature = "debug_tick")]
@@ -135,6 +216,11 @@ impl<'a> Core<'a> {
                 defmt::error!("Failed to send mouse report: {:?}", e);
             }
         }
+        let now = Instant::now().as_millis();
+        let transition = self.auto_mouse.on_motion(self.mouse.take_motion(), now);
+        self.apply_auto_mouse(transition);
+        let transition = self.auto_mouse.tick(now);
+        self.apply_auto_mouse(transition);
 
         // Process all events in the layout channel if any
         // This is where the keymap is processed
@@ -170,6 +256,19 @@ impl<'a> Core<'a> {
         }
     }
 
+    /// Send a command to the sensor, on the right half
+    /// From the left half, it is forwarded to the other side.
+    async fn send_sensor_command(&self, cmd: SensorCommand) {
+        if self.is_right {
+            cmd.send_local();
+            return;
+        }
+        if SIDE_CHANNEL.is_full() {
+            defmt::error!("Side channel is full");
+        }
+        SIDE_CHANNEL.send(Event::SensorCommand(cmd.to_u8())).await;
+    }
+
     /// Process a custom event from the layout
     async fn process_custom_event(&mut self, event: KbCustomEvent<CustomEvent>) {
         match event {
@@ -198,19 +297,46 @@ impl<'a> Core<'a> {
                 self.mouse.on_ball_is_wheel(false);
             }
             KbCustomEvent::Press(CustomEvent::IncreaseCpi) => {
-                if SENSOR_CMD_CHANNEL.is_full() {
-                    defmt::error!("Sensor channel is full");
-                }
-                SENSOR_CMD_CHANNEL.send(SensorCommand::IncreaseCpi).await;
+                self.send_sensor_command(SensorCommand::IncreaseCpi).await;
             }
             KbCustomEvent::Release(CustomEvent::IncreaseCpi) => {}
             KbCustomEvent::Press(CustomEvent::DecreaseCpi) => {
-                if SENSOR_CMD_CHANNEL.is_full() {
-                    defmt::error!("Sensor channel is full");
-                }
-                SENSOR_CMD_CHANNEL.send(SensorCommand::DecreaseCpi).await;
+                self.send_sensor_command(SensorCommand::DecreaseCpi).await;
             }
             KbCustomEvent::Release(CustomEvent::DecreaseCpi) => {}
+            KbCustomEvent::Press(CustomEvent::NextCpi) => {
+                self.send_sensor_command(SensorCommand::NextCpi).await;
+            }
+            KbCustomEvent::Release(CustomEvent::NextCpi) => {}
+            KbCustomEvent::Press(CustomEvent::PreviousCpi) => {
+                self.send_sensor_command(SensorCommand::PreviousCpi).await;
+            }
+            KbCustomEvent::Release(CustomEvent::PreviousCpi) => {}
+            KbCustomEvent::Press(CustomEvent::NextSnipingCpi) => {
+                self.send_sensor_command(SensorCommand::NextSnipingCpi)
+                    .await;
+            }
+            KbCustomEvent::Release(CustomEvent::NextSnipingCpi) => {}
+            KbCustomEvent::Press(CustomEvent::PreviousSnipingCpi) => {
+                self.send_sensor_command(SensorCommand::PreviousSnipingCpi)
+                    .await;
+            }
+            KbCustomEvent::Release(CustomEvent::PreviousSnipingCpi) => {}
+            KbCustomEvent::Press(CustomEvent::Sniping) => {
+                self.send_sensor_command(SensorCommand::Sniping(true)).await;
+            }
+            KbCustomEvent::Release(CustomEvent::Sniping) => {
+                self.send_sensor_command(SensorCommand::Sniping(false))
+                    .await;
+            }
+            KbCustomEvent::Press(CustomEvent::NextAccelProfile) => {
+                self.mouse.on_next_accel_profile();
+            }
+            KbCustomEvent::Release(CustomEvent::NextAccelProfile) => {}
+            KbCustomEvent::Press(CustomEvent::PreviousAccelProfile) => {
+                self.mouse.on_previous_accel_profile();
+            }
+            KbCustomEvent::Release(CustomEvent::PreviousAccelProfile) => {}
 
             KbCustomEvent::Press(CustomEvent::NextLedAnimation) => {
                 if ANIM_CHANNEL.is_full() {
@@ -225,6 +351,14 @@ impl<'a> Core<'a> {
             }
             KbCustomEvent::Release(CustomEvent::ResetToUsbMassStorage) => {}
 
+            KbCustomEvent::Press(CustomEvent::DumpProtocolStats) => {
+                if SIDE_CMD_CHANNEL.is_full() {
+                    defmt::error!("Side command channel is full");
+                }
+                SIDE_CMD_CHANNEL.send(SideCommand::DumpStats).await;
+            }
+            KbCustomEvent::Release(CustomEvent::DumpProtocolStats) => {}
+
             KbCustomEvent::NoEvent => (),
         }
     }
@@ -1,5 +1,6 @@
 use crate::core::LAYOUT_CHANNEL;
 use crate::device::is_host;
+use crate::mouse::SCROLL_RESOLUTION;
 use defmt::*;
 use embassy_executor::Spawner;
 use embassy_rp::peripherals::USB;
@@ -85,8 +86,18 @@ pub const MOUSE_REPORT_DESCRIPTOR: &[u8] = &[
     0x95, 0x02,        //     Report Count (2)
     0x81, 0x06,        //     Input (Data,Var,Rel,No Wrap,Linear,Preferred State,No Null Position)
     0xC0,              //   End Collection
-    0xA1, 0x00,        //   Collection (Physical)
+    0xA1, 0x02,        //   Collection (Logical)
     0x05, 0x01,        //     Usage Page (Generic Desktop Ctrls)
+    0x09, 0x48,        //     Usage (Resolution Multiplier)
+    0x15, 0x00,        //     Logical Minimum (0)
+    0x25, 0x01,        //     Logical Maximum (1)
+    0x35, 0x01,        //     Physical Minimum (1)
+    0x45, 0x78,        //     Physical Maximum (120)
+    0x75, 0x02,        //     Report Size (2)
+    0x95, 0x01,        //     Report Count (1)
+    0xB1, 0x02,        //     Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+    0x35, 0x00,        //     Physical Minimum (0)
+    0x45, 0x00,        //     Physical Maximum (0)
     0x09, 0x38,        //     Usage (Wheel)
     0x15, 0x81,        //     Logical Minimum (-127)
     0x25, 0x7F,        //     Logical Maximum (127)
@@ -94,7 +105,19 @@ pub const MOUSE_REPORT_DESCRIPTOR: &[u8] = &[
     0x95, 0x01,        //     Report Count (1)
     0x81, 0x06,        //     Input (Data,Var,Rel,No Wrap,Linear,Preferred State,No Null Position)
     0xC0,              //   End Collection
-    0xA1, 0x00,        //   Collection (Physical)
+    0xA1, 0x02,        //   Collection (Logical)
+    0x09, 0x48,        //     Usage (Resolution Multiplier)
+    0x15, 0x00,        //     Logical Minimum (0)
+    0x25, 0x01,        //     Logical Maximum (1)
+    0x35, 0x01,        //     Physical Minimum (1)
+    0x45, 0x78,        //     Physical Maximum (120)
+    0x75, 0x02,        //     Report Size (2)
+    0x95, 0x01,        //     Report Count (1)
+    0xB1, 0x02,        //     Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+    0x35, 0x00,        //     Physical Minimum (0)
+    0x45, 0x00,        //     Physical Maximum (0)
+    0x75, 0x04,        //     Report Size (4)
+    0xB1, 0x03,        //     Feature (Const,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
     0x05, 0x0C,        //     Usage Page (Consumer)
     0x0A, 0x38, 0x02,  //     Usage (AC Pan)
     0x95, 0x01,        //     Report Count (1)
@@ -104,7 +127,7 @@ pub const MOUSE_REPORT_DESCRIPTOR: &[u8] = &[
     0x81, 0x06,        //     Input (Data,Var,Rel,No Wrap,Linear,Preferred State,No Null Position)
     0xC0,              //   End Collection
     0xC0,              // End Collection
-// 87 bytes
+// 131 bytes
 ];
 
 /// Keyboard HID report
@@ -218,6 +241,38 @@ impl RequestHandler for HidRequestHandler<'_> {
     }
 }
 
+/// HID handler of the mouse, for the resolution multipliers of the wheels
+#[derive(Default)]
+pub struct MouseRequestHandler {
+    /// Feature report last set by the host
+    feature: u8,
+}
+
+impl RequestHandler for MouseRequestHandler {
+    fn get_report(&mut self, id: ReportId, buf: &mut [u8]) -> Option<usize> {
+        info!("Get mouse report for {:?}", id);
+        match (id, buf.first_mut()) {
+            (ReportId::Feature(0), Some(byte)) => {
+                *byte = self.feature;
+                Some(1)
+            }
+            _ => None,
+        }
+    }
+
+    fn set_report(&mut self, id: ReportId, data: &[u8]) -> OutResponse {
+        info!("Set mouse report for {:?}: {=[u8]}", id, data);
+        match (id, data.first()) {
+            (ReportId::Feature(0), Some(&feature)) => {
+                self.feature = feature;
+                SCROLL_RESOLUTION.signal(feature);
+                OutResponse::Accepted
+            }
+            _ => OutResponse::Rejected,
+        }
+    }
+}
+
 #[embassy_executor::task]
 async fn caps_lock_change() {
     // send a key press and release event for the CapsLock key so that
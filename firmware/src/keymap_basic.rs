@@ -9,6 +9,9 @@ pub const NB_LAYERS: usize = 2;
 /// Keyboard Layout type to mask the number of layers
 pub type KBLayout = Layout<FULL_COLS, ROWS, NB_LAYERS, CustomEvent>;
 
+/// No automatic mouse layer
+pub const AUTO_MOUSE_KEY: Option<(u8, u8)> = None;
+
 /// Mouse left click
 const MLC: Action<CustomEvent> = Action::Custom(MouseLeftClick);
 /// Mouse right click
@@ -26,6 +29,8 @@ const DEC: Action<CustomEvent> = Action::Custom(DecreaseCpi);
 const RGB: Action<CustomEvent> = Action::Custom(NextLedAnimation);
 /// Reset to USB Mass Storage
 const RST: Action<CustomEvent> = Action::Custom(ResetToUsbMassStorage);
+/// Log the statistics of the protocol between the halves
+const STA: Action<CustomEvent> = Action::Custom(DumpProtocolStats);
 
 #[rustfmt::skip]
 /// Layout
@@ -39,6 +44,6 @@ pub static LAYERS: keyberon::layout::Layers<FULL_COLS, ROWS, NB_LAYERS, CustomEv
         [ n  n  n  n  n      n  n  n  n  n ],
         [ n  n  n  n  n      n  n  n  n  n ],
         [ n  n  n  n  n      n  n  n  n  n ],
-        [ n {BIW} {INC} {DEC} {MLC}      {MRC} {MMC} {RGB} n {RST} ],
+        [ n {BIW} {INC} {DEC} {MLC}      {MRC} {MMC} {RGB} {STA} {RST} ],
     }
 };
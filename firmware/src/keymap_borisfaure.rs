@@ -14,6 +14,10 @@ pub const NB_LAYERS: usize = 9;
 /// Keyboard Layout type to mask the number of layers
 pub type KBLayout = Layout<FULL_COLS, ROWS, NB_LAYERS, CustomEvent>;
 
+/// Virtual key pressed while the ball moves, to turn the MISC layer on.  It is
+/// not wired, and is set to `AML` on every layer.
+pub const AUTO_MOUSE_KEY: Option<(u8, u8)> = Some((3, 9));
+
 /// Timeout to consider a key as held
 const TIMEOUT: u16 = 200;
 /// Disable tap_hold_interval
@@ -108,6 +112,9 @@ const HT_5_T: Action<CustomEvent> = ht!(l(L_TMUX), k(T));
 /// Shift-Insert
 const S_INS: Action<CustomEvent> = m(&[LShift, Insert].as_slice());
 
+/// Automatic mouse layer, on the virtual key `AUTO_MOUSE_KEY`
+const AML: Action<CustomEvent> = l(L_MISC);
+
 /// Caps Mode
 const CAPS: Action<CustomEvent> = k(CapsLock);
 /// Caps from LED change
@@ -323,6 +330,20 @@ const BIW: Action<CustomEvent> = Action::Custom(BallIsWheel);
 const MINC: Action<CustomEvent> = Action::Custom(IncreaseCpi);
 /// Decrease CPI
 const MDEC: Action<CustomEvent> = Action::Custom(DecreaseCpi);
+/// Next pointer acceleration profile
+const MACC: Action<CustomEvent> = Action::Custom(NextAccelProfile);
+/// Previous pointer acceleration profile
+const MDCC: Action<CustomEvent> = Action::Custom(PreviousAccelProfile);
+/// Next CPI of the normal set
+const MNXC: Action<CustomEvent> = Action::Custom(NextCpi);
+/// Previous CPI of the normal set
+const MPVC: Action<CustomEvent> = Action::Custom(PreviousCpi);
+/// Next CPI of the sniping set
+const SNXC: Action<CustomEvent> = Action::Custom(NextSnipingCpi);
+/// Previous CPI of the sniping set
+const SPVC: Action<CustomEvent> = Action::Custom(PreviousSnipingCpi);
+/// Sniping, while held
+const SNP: Action<CustomEvent> = Action::Custom(Sniping);
 
 /// RGB LED control
 const RGB: Action<CustomEvent> = Action::Custom(NextLedAnimation);
@@ -337,46 +358,46 @@ pub static LAYERS: keyberon::layout::Layers<FULL_COLS, ROWS, NB_LAYERS, CustomEv
 [  Q         {HT_W_W}   F          P         {HT_4_B}    {HT_4_K}   L         U  {HT_W_Y}     ;        ],
 [ {HT_C_A}    R         S         {HT_5_T}    G           M        {HT_3_N}   E  {HT_4_I}    {HT_C_O}  ],
 [ {HT_S_Z}   {HT_A_X}   C          D         {HT_3_V}    {HT_3_J}   H         ,  {HT_A_DOT}  {HT_S_SL} ],
-[ {VCAPS}    {VNUM}    {HT_3_ESC} {HT_1_SP}   Tab         Enter    {HT_2_BS}  n    n          n        ],
+[ {VCAPS}    {VNUM}    {HT_3_ESC} {HT_1_SP}   Tab         Enter    {HT_2_BS}  n    n          {AML}    ],
     } { /* 1: LOWER */
         [ !  #  $    '(' ')'        ^  &  {S_INS}    *      ~   ],
         [ =  -  '`'  '{' '}'        n  n   PgUp    PgDown  '\\' ],
         [ @  &  %    '[' ']'        n  n     n      '\''    '"' ],
-        [ n  n  t     t   t         Enter Space  n   n      n   ],
+        [ n  n  t     t   t         Enter Space  n   n      {AML} ],
     } { /* 2: RAISE */
         [ {QWERTY}  n    {E_ACU}  {E_CIR}  {E_GRV}      PgUp   {U_GRV}  {I_CIR}  {O_CIR}  Home  ],
         [ {A_GRV}  '_'    +        &        |           RAlt    Left     Up       Down    Right ],
         [ {EURO}   {OE}  {C_CED}  {CAPS}   {NUMLCK}     PgDown  Menu    PScreen  {DOTS}   End   ],
-        [ {VCAPS} {VNUM} Escape   BSpace    Tab         t       t        n        n       n     ],
+        [ {VCAPS} {VNUM} Escape   BSpace    Tab         t       t        n        n       {AML} ],
     } { /* 3: NUMBERS Fx */
         [ .  4  5  6  =                       /  F1  F2   F3   F4  ],
         [ 0  1  2  3  -                       *  F5  F6   F7   F8  ],
         [ ,  7  8  9  +                       +  F9  F10  F11  F12 ],
-        [ n {VUNNUM} {UNNUM} {HT_1_SP} Tab  Enter {HT_2_BS} n n n  ],
+        [ n {VUNNUM} {UNNUM} {HT_1_SP} Tab  Enter {HT_2_BS} n n {AML} ],
     } { /* 4: MISC or Mouse */
-        [ Pause  {GAME}           {COLEMAN}    {QWERTY}      n       {MINC} {MWC}   n   n   n  ],
-        [ {RGB}  VolDown          Mute         VolUp         n        n     {BIW} {MLC} n {MRC}],
-        [ {RST} MediaPreviousSong MediaPlayPause MediaNextSong n     {MDEC} {MWC}   n   n {RST}],
-        [  n     n                {MLC}        {MWC}      {MRC}      {MLC}  {MRC}   n   n   n  ],
+        [ Pause  {GAME}           {COLEMAN}    {QWERTY}      n       {MINC} {MWC} {MACC} {MNXC} {SNXC}],
+        [ {RGB}  VolDown          Mute         VolUp         n       {SNP}  {BIW} {MLC}  {SPVC} {MRC} ],
+        [ {RST} MediaPreviousSong MediaPlayPause MediaNextSong n     {MDEC} {MWC} {MDCC} {MPVC} {RST} ],
+        [  n     n                {MLC}        {MWC}      {MRC}      {MLC}  {MRC}   n   n   {AML} ],
     } { /* 5: TMUX */
         [ {T_6}   {T_7} {T_8}   {T_9}   {T_0}      {T_1}   {T_2}  {T_3}   {T_4}   {T_5}   ],
         [ {T_LST}  n     n       n       n          n     {T_PRV} {T_UP}  {T_DWN} {T_NXT} ],
         [  n       n    {T_NEW} {T_CPY} {T_PST}     n       n     {T_RNM} {T_MOV} {T_PST} ],
-        [  n       n     t       t       t         {T_CMD}  n      n       n       n      ],
+        [  n       n     t       t       t         {T_CMD}  n      n       n       {AML}  ],
     } { /* 6: Gaming */
         [ Q    W  E   R         T            {HT_4_Y}   U      I  {HT_W_O}     P       ],
         [ A    S  D   F         G             H         J      K   L         {HT_C_SC} ],
         [ Z    X  C   V         B             N         M      ,  {HT_A_DOT} {HT_S_SL} ],
-        [ {VCAPS} {VNUM} {HT_3_ESC} {HT_1_SP} Tab      Enter {HT_2_BS}  n    n    n    ],
+        [ {VCAPS} {VNUM} {HT_3_ESC} {HT_1_SP} Tab      Enter {HT_2_BS}  n    n    {AML} ],
     } { /* 7: Caps */
 [  Q         {HT_W_W}   F         P         {HT_4_B}    {HT_4_K}   L        U  {HT_W_Y}     ;        ],
 [ {HT_C_A}    R         S        {HT_5_T}    G           M         N        E   I          {HT_C_O}  ],
 [ {HT_S_Z}   {HT_A_X}   C         D         {HT_3_V}    {HT_3_J}   H        ,  {HT_A_DOT}  {HT_S_SL} ],
-[ {VUNCAPS}   n        {UNCAPS}  {HT_1_SP}  '_'          Enter  {HT_2_BS}   n   n           n        ],
+[ {VUNCAPS}   n        {UNCAPS}  {HT_1_SP}  '_'          Enter  {HT_2_BS}   n   n           {AML}    ],
     } { /* 8: QWERTY */
 [  Q        {HT_W_W}   E       R         {HT_4_T}       {HT_4_Y}   U       I  {HT_W_O}     P        ],
 [ {HT_C_A}   S         D      {HT_5_F}    G              H         J       K   L          {HT_C_SC} ],
 [ {HT_S_Z}  {HT_A_X}   C       V         {HT_3_B}       {HT_3_N}   M       ,  {HT_A_DOT}  {HT_S_SL} ],
-[  n         n        Escape  {HT_1_SP}   Tab            Enter  {HT_2_BS}  n   n           n        ],
+[  n         n        Escape  {HT_1_SP}   Tab            Enter  {HT_2_BS}  n   n           {AML}    ],
     }
 };
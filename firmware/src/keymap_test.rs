@@ -14,6 +14,9 @@ pub const NB_LAYERS: usize = 2;
 /// Keyboard Layout type to mask the number of layers
 pub type KBLayout = Layout<FULL_COLS, ROWS, NB_LAYERS, CustomEvent>;
 
+/// No automatic mouse layer
+pub const AUTO_MOUSE_KEY: Option<(u8, u8)> = None;
+
 /// A shortcut to create a `Action::Sequence`, useful to
 /// create compact layout.
 const fn seq<T, K>(events: &'static &'static [SequenceEvent<K>]) -> Action<T, K>
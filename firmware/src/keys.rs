@@ -3,23 +3,31 @@ use crate::device::is_host;
 use crate::rgb_leds::RGB_CHANNEL;
 use crate::side::SIDE_CHANNEL;
 use embassy_rp::gpio::{Input, Output};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
 use embassy_time::{Duration, Ticker};
 use keyberon::debounce::Debouncer;
 use keyberon::layout::Event as KBEvent;
+use utils::geometry::GEOMETRY;
+use utils::key_matrix::KeyMatrix;
 use utils::serde::Event;
 
 /// Keyboard matrix rows
-pub const ROWS: usize = 4;
+pub const ROWS: usize = GEOMETRY.rows;
 /// Keyboard matrix columns
-pub const COLS: usize = 5;
+pub const COLS: usize = GEOMETRY.half_cols;
 /// Full number of columns
-pub const FULL_COLS: usize = 2 * COLS;
+pub const FULL_COLS: usize = GEOMETRY.cols();
 /// Keyboard matrix refresh rate, in Hz
 const REFRESH_RATE: u16 = 1000;
 /// Keyboard matrix debouncing time, in ms
 const DEBOUNCE_TIME_MS: u16 = 5;
 /// Keyboard bounce number
 const NB_BOUNCE: u16 = REFRESH_RATE * DEBOUNCE_TIME_MS / 1000;
+/// Number of scans between two snapshots of the keys held sent to the host
+const SNAPSHOT_PERIOD: u16 = REFRESH_RATE;
+
+/// Signal to send the keys held to the other side, after it restarted
+pub static RESYNC_KEYS: Signal<CriticalSectionRawMutex, ()> = Signal::new();
 
 /// Pins for the keyboard matrix
 pub struct Matrix<'a> {
@@ -60,6 +68,7 @@ impl<'a> Matrix<'a> {
 pub async fn matrix_scanner(mut matrix: Matrix<'_>, is_right: bool) {
     let mut ticker = Ticker::every(Duration::from_hz(REFRESH_RATE.into()));
     let mut debouncer = Debouncer::new(matrix_state_new(), matrix_state_new(), NB_BOUNCE);
+    let mut snapshot_countdown = SNAPSHOT_PERIOD;
 
     loop {
         let transform = if is_right {
@@ -72,7 +81,7 @@ pub async fn matrix_scanner(mut matrix: Matrix<'_>, is_right: bool) {
                             _ => panic!("Invalid key {:?}", (r, c)),
                         }
                     } else {
-                        (r, 9 - c)
+                        (r, FULL_COLS as u8 - 1 - c)
                     }
                 })
             }
@@ -131,6 +140,30 @@ pub async fn matrix_scanner(mut matrix: Matrix<'_>, is_right: bool) {
             }
         }
 
+        if !is_host {
+            snapshot_countdown -= 1;
+            // The host fixes the keys it got stuck from the snapshot, and the
+            // other side no longer knows the keys held after it restarted
+            if RESYNC_KEYS.try_take().is_some() || snapshot_countdown == 0 {
+                snapshot_countdown = SNAPSHOT_PERIOD;
+                let mut keys = KeyMatrix::new();
+                for (r, row) in debouncer.get().iter().enumerate() {
+                    for (c, pressed) in row.iter().enumerate() {
+                        if !*pressed {
+                            continue;
+                        }
+                        if let KBEvent::Press(r, c) = transform(KBEvent::Press(r as u8, c as u8)) {
+                            keys.set(r, c, true);
+                        }
+                    }
+                }
+                if SIDE_CHANNEL.is_full() {
+                    defmt::error!("Side channel is full");
+                }
+                SIDE_CHANNEL.send(Event::KeySnapshot(keys)).await;
+            }
+        }
+
         ticker.next().await;
     }
 }
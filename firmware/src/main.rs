@@ -118,6 +118,7 @@ async fn main(spawner: Spawner) {
 
     let mut state_kb = State::new();
     let mut state_mouse = State::new();
+    let mut mouse_request_handler = hid::MouseRequestHandler::default();
 
     let mut builder = Builder::new(
         driver,
@@ -144,7 +145,7 @@ async fn main(spawner: Spawner) {
 
     let hidm_config = HidConfig {
         report_descriptor: MOUSE_REPORT_DESCRIPTOR,
-        request_handler: None,
+        request_handler: Some(&mut mouse_request_handler),
         poll_ms: 10,
         max_packet_size: 7,
     };
@@ -183,7 +184,8 @@ async fn main(spawner: Spawner) {
     // Disable the status LED on startup
     status_led.set_high();
 
-    let full_duplex_fut = side::full_duplex_comm(
+    #[cfg(not(feature = "half_duplex"))]
+    let sides_fut = side::full_duplex_comm(
         pio1.common,
         pio1.sm0,
         pio1.sm1,
@@ -192,6 +194,15 @@ async fn main(spawner: Spawner) {
         &mut status_led,
         is_right,
     );
+    #[cfg(feature = "half_duplex")]
+    let sides_fut = side::half_duplex_comm(
+        pio1.common,
+        pio1.sm0,
+        pio1.sm1,
+        p.PIN_1,
+        &mut status_led,
+        is_right,
+    );
     let pio0 = Pio::new(p.PIO0, PioIrq0);
     let rgb_leds_fut = rgb_leds::run(pio0.common, pio0.sm0, p.DMA_CH0, p.PIN_0, is_right);
     let mut core = Core::new(hid_mouse, is_right);
@@ -222,7 +233,7 @@ async fn main(spawner: Spawner) {
         let ball_sensor_fut = ball.run();
         defmt::info!("let's go!");
         future::join3(
-            future::join3(usb_fut, full_duplex_fut, rgb_leds_fut),
+            future::join3(usb_fut, sides_fut, rgb_leds_fut),
             future::join(hid_kb_reader_fut, hid_kb_writer_fut),
             future::join3(matrix_fut, layout_fut, ball_sensor_fut),
         )
@@ -230,7 +241,7 @@ async fn main(spawner: Spawner) {
     } else {
         defmt::info!("let's go!");
         future::join3(
-            future::join3(usb_fut, full_duplex_fut, rgb_leds_fut),
+            future::join3(usb_fut, sides_fut, rgb_leds_fut),
             future::join(hid_kb_reader_fut, hid_kb_writer_fut),
             future::join(matrix_fut, layout_fut),
         )
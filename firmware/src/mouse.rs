@@ -1,21 +1,22 @@
 use crate::device::is_host;
 use crate::hid::MouseReport;
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
-
-/// Mouse move event
-#[derive(Debug, defmt::Format)]
-pub struct MouseMove {
-    /// Delta X
-    pub dx: i16,
-    /// Delta Y
-    pub dy: i16,
-}
+use crate::side::SIDE_CHANNEL;
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel, signal::Signal,
+};
+use embassy_time::Instant;
+use utils::acceleration::{Accelerator, Profile, UNIT};
+pub use utils::mouse_move::MouseMove;
+use utils::scroll::{resolution_multipliers, Inertia, Scroll, ScrollConfig, SPEED_UNIT};
+use utils::serde::Event;
 
 /// Maximum number of movements in the channel
 pub const NB_MOVE: usize = 8;
 /// Channel to send movement reports from the sensor
 pub static MOUSE_MOVE_CHANNEL: Channel<CriticalSectionRawMutex, MouseMove, NB_MOVE> =
     Channel::new();
+/// Resolution multipliers set by the host, as found in the feature report
+pub static SCROLL_RESOLUTION: Signal<CriticalSectionRawMutex, u8> = Signal::new();
 
 /// Mouse handler
 pub struct MouseHandler {
@@ -29,17 +30,71 @@ pub struct MouseHandler {
     /// Moving the ball is actually moving the wheel
     ball_is_wheel: bool,
 
+    /// Acceleration applied to the pointer
+    accel: Accelerator,
+    /// Index of the acceleration profile in `ACCEL_PROFILES`
+    accel_profile: usize,
+
+    /// Turns the movements of the ball into wheel and pan units
+    scroll: Scroll,
+
     /// Direction X
     dx: i16,
     /// Direction Y
     dy: i16,
+    /// Vertical wheel
+    wheel: i8,
+    /// Horizontal wheel
+    pan: i8,
 
     /// Whether the state has changed
     changed: bool,
+    /// Motion of the ball not yet taken by `take_motion()`
+    motion: MouseMove,
+
+    /// Movement not yet forwarded to the other half, when not the host
+    forward: MouseMove,
+    /// Ticks left before forwarding the movement to the other half
+    forward_tick: usize,
 }
 
-/// Threshold to consider the movement as a wheel movement
-const WHEEL_THRESHOLD: i16 = 16;
+/// Scrolling with the ball: counts of the sensor per detent of the wheels,
+/// locking gestures to their dominant axis after 8 counts, and inertia losing
+/// a fifth of its speed every 10ms
+const SCROLL_CONFIG: ScrollConfig = ScrollConfig {
+    vertical_divisor: 16,
+    horizontal_divisor: 16,
+    axis_lock: Some(8),
+    inertia: Some(Inertia {
+        decay: SPEED_UNIT * 4 / 5,
+        min_speed: SPEED_UNIT,
+    }),
+};
+
+/// Forward the movement to the other half every X ticks, to keep the link
+/// from being flooded
+const TICK_FORWARD: usize = 8;
+
+/// Gains of the lookup table profile, by speed in counts per report
+const ACCEL_TABLE: [(u32, i32); 4] = [(2, UNIT), (8, UNIT * 3 / 2), (20, 3 * UNIT), (40, 4 * UNIT)];
+
+/// Acceleration profiles to switch between, the first one being the default
+const ACCEL_PROFILES: [Profile; 4] = [
+    Profile::Linear { gain: UNIT },
+    Profile::Power {
+        base: UNIT,
+        scale: 20,
+        exponent: 2,
+        max: 4 * UNIT,
+    },
+    Profile::Sigmoid {
+        min: UNIT,
+        max: 3 * UNIT,
+        mid: 15,
+        width: 5,
+    },
+    Profile::Table(&ACCEL_TABLE),
+];
 
 /// Empty mouse report
 const MOUSE_REPORT_EMPTY: MouseReport = MouseReport {
@@ -58,9 +113,17 @@ impl MouseHandler {
             right_click: false,
             wheel_click: false,
             ball_is_wheel: false,
+            accel: Accelerator::new(ACCEL_PROFILES[0]),
+            accel_profile: 0,
+            scroll: Scroll::new(SCROLL_CONFIG),
             dx: 0,
             dy: 0,
+            wheel: 0,
+            pan: 0,
             changed: false,
+            motion: MouseMove::new(0, 0),
+            forward: MouseMove::new(0, 0),
+            forward_tick: TICK_FORWARD,
         }
     }
 
@@ -85,23 +148,110 @@ impl MouseHandler {
     /// On Ball is wheel
     pub fn on_ball_is_wheel(&mut self, is_pressed: bool) {
         self.ball_is_wheel = is_pressed;
+        // Inertia goes on after releasing the key, until the ball moves again
+        if is_pressed {
+            self.scroll.reset();
+        }
         self.changed = true;
     }
 
+    /// Switch to the next acceleration profile
+    pub fn on_next_accel_profile(&mut self) {
+        self.set_accel_profile((self.accel_profile + 1) % ACCEL_PROFILES.len());
+    }
+
+    /// Switch to the previous acceleration profile
+    pub fn on_previous_accel_profile(&mut self) {
+        self.set_accel_profile(
+            (self.accel_profile + ACCEL_PROFILES.len() - 1) % ACCEL_PROFILES.len(),
+        );
+    }
+
+    /// Switch to the acceleration profile at the given index
+    fn set_accel_profile(&mut self, idx: usize) {
+        defmt::info!("Acceleration profile: {:?}", ACCEL_PROFILES[idx]);
+        self.accel_profile = idx;
+        self.accel.set_profile(ACCEL_PROFILES[idx]);
+    }
+
     /// Handle a mouse movement event
-    fn handle_move_event(&mut self, MouseMove { dx, dy }: MouseMove) {
-        self.dx = dx;
-        self.dy = dy;
+    fn handle_move_event(&mut self, mouse_move: MouseMove) {
+        self.motion.dx = self.motion.dx.saturating_add(mouse_move.dx);
+        self.motion.dy = self.motion.dy.saturating_add(mouse_move.dy);
+        if self.ball_is_wheel {
+            let now = Instant::now().as_millis();
+            let (wheel, pan) = self.scroll.accumulate(mouse_move, now);
+            self.wheel = self.wheel.saturating_add(wheel);
+            self.pan = self.pan.saturating_add(pan);
+        } else {
+            self.scroll.reset();
+            let MouseMove { dx, dy } = self.accel.apply(mouse_move);
+            self.dx = self.dx.saturating_add(dx);
+            self.dy = self.dy.saturating_add(dy);
+        }
         self.changed = true;
     }
 
+    /// Motion of the ball since the last call, whether it moved the pointer
+    /// or scrolled
+    pub fn take_motion(&mut self) -> MouseMove {
+        core::mem::replace(&mut self.motion, MouseMove::new(0, 0))
+    }
+
+    /// Keep scrolling by inertia once the ball has stopped
+    fn coast(&mut self) {
+        let (wheel, pan) = self.scroll.coast(Instant::now().as_millis());
+        if (wheel, pan) != (0, 0) {
+            self.wheel = self.wheel.saturating_add(wheel);
+            self.pan = self.pan.saturating_add(pan);
+            self.changed = true;
+        }
+    }
+
+    /// Accumulate a movement to forward to the other half
+    fn accumulate_move_event(&mut self, MouseMove { dx, dy }: MouseMove) {
+        self.forward.dx = self.forward.dx.saturating_add(dx);
+        self.forward.dy = self.forward.dy.saturating_add(dy);
+    }
+
+    /// Forward the accumulated movement to the other half, which is the host
+    async fn forward_move(&mut self) {
+        self.forward_tick -= 1;
+        if self.forward_tick > 0 {
+            return;
+        }
+        self.forward_tick = TICK_FORWARD;
+        if self.forward == MouseMove::new(0, 0) {
+            return;
+        }
+        if SIDE_CHANNEL.is_full() {
+            defmt::error!("Side channel is full");
+        }
+        SIDE_CHANNEL.send(Event::MouseMove(self.forward)).await;
+        self.forward = MouseMove::new(0, 0);
+    }
+
     /// Compute the state of the mouse. Called every 1ms
     pub async fn tick(&mut self) -> Option<MouseReport> {
+        if !is_host() {
+            if let Ok(event) = MOUSE_MOVE_CHANNEL.try_receive() {
+                self.accumulate_move_event(event);
+            }
+            self.forward_move().await;
+            return None;
+        }
+        if let Some(feature) = SCROLL_RESOLUTION.try_take() {
+            let multipliers = resolution_multipliers(feature);
+            defmt::info!("Scroll resolution multipliers: {:?}", multipliers);
+            self.scroll.set_multipliers(multipliers);
+        }
         if let Ok(event) = MOUSE_MOVE_CHANNEL.try_receive() {
             self.handle_move_event(event);
             self.changed = true;
+        } else {
+            self.coast();
         }
-        if self.changed && is_host() {
+        if self.changed {
             self.changed = false;
             let hid_report = self.generate_hid_report();
             Some(hid_report)
@@ -113,15 +263,12 @@ impl MouseHandler {
     /// Generate a HID report for the mouse
     fn generate_hid_report(&mut self) -> MouseReport {
         let mut report = MOUSE_REPORT_EMPTY;
-        if self.ball_is_wheel {
-            match self.dy {
-                y if y > WHEEL_THRESHOLD => report.wheel = -1,
-                y if y < -WHEEL_THRESHOLD => report.wheel = 1,
-                _ => {}
-            }
-        } else {
-            report.x = self.dx;
-            report.y = self.dy;
+        // Movements are relative: each one is only reported once
+        report.wheel = core::mem::take(&mut self.wheel);
+        report.pan = core::mem::take(&mut self.pan);
+        report.x = core::mem::take(&mut self.dx);
+        report.y = core::mem::take(&mut self.dy);
+        if !self.ball_is_wheel {
             if self.left_click {
                 report.buttons |= 1;
             }
@@ -8,6 +8,7 @@ use embassy_rp::spi::{Error as SpiError, Instance as SpiInstance, Mode, Spi};
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
 use embassy_time::{Duration, Ticker, Timer};
 use embedded_hal::spi::SpiBus;
+use utils::cpi::{Cpi, CpiConfig};
 
 /// Maximum number of commands in the channel
 pub const NB_CMD: usize = 64;
@@ -18,16 +19,73 @@ pub static SENSOR_CMD_CHANNEL: Channel<CriticalSectionRawMutex, SensorCommand, N
 
 const DEFAULT_CPI: u16 = 800;
 
+/// CPI values cycled through, like on the QMK firmware of the Charybdis
+const CPI_CONFIG: CpiConfig = CpiConfig {
+    normal: &[
+        400, 600, 800, 1000, 1200, 1400, 1600, 1800, 2000, 2400, 2800, 3200,
+    ],
+    sniping: &[200, 300, 400, 500],
+};
+
 /// Default angle tune value, the sensor will be turned 32 degrees
 const DEFAULT_ANGLE_TUNE: u8 = 32;
 
 /// Sensor refresh rate, in ms
 const REFRESH_RATE_MS: u64 = 10;
 
-#[derive(Debug, defmt::Format)]
+#[derive(Debug, Clone, Copy, defmt::Format)]
 pub enum SensorCommand {
     IncreaseCpi,
     DecreaseCpi,
+    /// Switch to the next normal CPI of the set
+    NextCpi,
+    /// Switch to the previous normal CPI of the set
+    PreviousCpi,
+    /// Switch to the next sniping CPI of the set
+    NextSnipingCpi,
+    /// Switch to the previous sniping CPI of the set
+    PreviousSnipingCpi,
+    /// Start or stop sniping, at a low CPI for precise pointing
+    Sniping(bool),
+}
+
+impl SensorCommand {
+    /// Code of the command, as sent to the half with the sensor
+    pub fn to_u8(&self) -> u8 {
+        match self {
+            SensorCommand::IncreaseCpi => 1,
+            SensorCommand::DecreaseCpi => 2,
+            SensorCommand::NextCpi => 3,
+            SensorCommand::PreviousCpi => 4,
+            SensorCommand::NextSnipingCpi => 5,
+            SensorCommand::PreviousSnipingCpi => 6,
+            SensorCommand::Sniping(false) => 7,
+            SensorCommand::Sniping(true) => 8,
+        }
+    }
+
+    /// Command from its code, see `to_u8`
+    pub fn from_u8(code: u8) -> Option<Self> {
+        match code {
+            1 => Some(SensorCommand::IncreaseCpi),
+            2 => Some(SensorCommand::DecreaseCpi),
+            3 => Some(SensorCommand::NextCpi),
+            4 => Some(SensorCommand::PreviousCpi),
+            5 => Some(SensorCommand::NextSnipingCpi),
+            6 => Some(SensorCommand::PreviousSnipingCpi),
+            7 => Some(SensorCommand::Sniping(false)),
+            8 => Some(SensorCommand::Sniping(true)),
+            _ => None,
+        }
+    }
+
+    /// Queue the command for the sensor of this half
+    /// The command is dropped if the channel is full, not to block the caller.
+    pub fn send_local(self) {
+        if SENSOR_CMD_CHANNEL.try_send(self).is_err() {
+            defmt::error!("Sensor channel is full, dropping {:?}", self);
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, defmt::Format)]
@@ -113,6 +171,8 @@ pub struct Pmw3360<'a, T: SpiInstance, M: Mode> {
     last_dx: i16,
     /// Last Dy value
     last_dy: i16,
+    /// CPI to run at
+    cpi: Cpi,
 }
 
 impl<'a, I: SpiInstance, M: Mode> Pmw3360<'a, I, M> {
@@ -124,6 +184,7 @@ impl<'a, I: SpiInstance, M: Mode> Pmw3360<'a, I, M> {
             in_burst: false,
             last_dx: 0,
             last_dy: 0,
+            cpi: Cpi::new(CPI_CONFIG, DEFAULT_CPI),
         }
     }
 
@@ -320,7 +381,7 @@ impl<'a, I: SpiInstance, M: Mode> Pmw3360<'a, I, M> {
     pub async fn start(&mut self) -> Result<(), Pmw3360Error> {
         self.power_up().await?;
         Timer::after_millis(35).await;
-        self.set_cpi(DEFAULT_CPI).await?;
+        self.set_cpi(self.cpi.current()).await?;
         Ok(())
     }
 
@@ -350,16 +411,20 @@ impl<'a, I: SpiInstance, M: Mode> Pmw3360<'a, I, M> {
                         defmt::error!("Error: {:?}", defmt::Debug2Format(&e));
                     }
                 }
-                Either::Second(event) => match event {
-                    SensorCommand::IncreaseCpi => {
-                        let cpi = self.get_cpi().await.unwrap_or(DEFAULT_CPI);
-                        let _ = self.set_cpi(cpi + 100).await;
-                    }
-                    SensorCommand::DecreaseCpi => {
-                        let cpi = self.get_cpi().await.unwrap_or(DEFAULT_CPI);
-                        let _ = self.set_cpi(cpi - 100).await;
+                Either::Second(event) => {
+                    let cpi = match event {
+                        SensorCommand::IncreaseCpi => self.cpi.step(100),
+                        SensorCommand::DecreaseCpi => self.cpi.step(-100),
+                        SensorCommand::NextCpi => self.cpi.next_normal(),
+                        SensorCommand::PreviousCpi => self.cpi.previous_normal(),
+                        SensorCommand::NextSnipingCpi => self.cpi.next_sniping(),
+                        SensorCommand::PreviousSnipingCpi => self.cpi.previous_sniping(),
+                        SensorCommand::Sniping(sniping) => self.cpi.set_sniping(sniping),
+                    };
+                    if let Err(e) = self.set_cpi(cpi).await {
+                        defmt::error!("Error: {:?}", defmt::Debug2Format(&e));
                     }
-                },
+                }
             }
         }
     }
@@ -1,3 +1,4 @@
+use crate::device::is_host;
 use crate::side::SIDE_CHANNEL;
 use embassy_futures::select::{select3, Either3};
 use embassy_rp::dma::{AnyChannel, Channel as DmaChannel};
@@ -35,6 +36,11 @@ pub enum AnimCommand {
     Error,
     /// Error has been fixed
     Fixed,
+    /// Seed the random number generator
+    Seed(u32),
+    /// Send the animation and the color layer to the other side, after it
+    /// restarted
+    Resync,
 }
 
 /// Channel to change the animation of the RGB LEDs
@@ -126,6 +132,8 @@ pub async fn run(
     let mut ticker = Ticker::every(Duration::from_hz(30));
 
     let mut anim = RgbAnim::new(is_right, clocks::rosc_freq());
+    // Color layer currently shown
+    let mut layer = 0;
     loop {
         match select3(RGB_CHANNEL.receive(), ANIM_CHANNEL.receive(), ticker.next()).await {
             Either3::First(event) => match event {
@@ -148,7 +156,8 @@ pub async fn run(
                 AnimCommand::Set(new_anim) => {
                     anim.set_animation(new_anim);
                 }
-                AnimCommand::ChangeLayer(layer) => {
+                AnimCommand::ChangeLayer(new_layer) => {
+                    layer = new_layer;
                     if layer == 0 {
                         anim.restore_animation();
                     } else {
@@ -161,6 +170,20 @@ pub async fn run(
                 AnimCommand::Fixed => {
                     anim.restore_animation();
                 }
+                AnimCommand::Seed(seed) => {
+                    anim.set_seed(seed);
+                }
+                AnimCommand::Resync => {
+                    if SIDE_CHANNEL.is_full() {
+                        defmt::error!("Side channel is full");
+                    }
+                    SIDE_CHANNEL.send(Event::RgbAnim(anim.animation())).await;
+                    // The host sets the layer, it is back to the first one
+                    // when the host restarts
+                    if is_host() {
+                        SIDE_CHANNEL.send(Event::RgbAnimChangeLayer(layer)).await;
+                    }
+                }
             },
             Either3::Third(_) => {
                 let data = anim.tick();
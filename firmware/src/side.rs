@@ -1,24 +1,63 @@
 use crate::core::LAYOUT_CHANNEL;
+use crate::device::is_host;
+use crate::keys::RESYNC_KEYS;
+use crate::mouse::MOUSE_MOVE_CHANNEL;
+use crate::pmw3360::SensorCommand;
 use crate::rgb_leds::{AnimCommand, ANIM_CHANNEL};
-use embassy_futures::select::{select, Either};
-use embassy_rp::clocks::clk_sys_freq;
+use embassy_futures::select::{select3, select4, Either3, Either4};
+use embassy_rp::clocks::{clk_sys_freq, rosc_freq};
+#[cfg(feature = "half_duplex")]
+use embassy_rp::gpio::Pull;
 use embassy_rp::gpio::{Level, Output};
-use embassy_rp::peripherals::{PIN_1, PIN_29, PIO1};
+#[cfg(not(feature = "half_duplex"))]
+use embassy_rp::peripherals::PIN_29;
+use embassy_rp::peripherals::{PIN_1, PIO1};
 use embassy_rp::pio::{self, Direction, FifoJoin, ShiftDirection, StateMachine};
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
-use embassy_time::Timer;
+use embassy_time::{Duration, Instant, Ticker, Timer};
 use fixed::{traits::ToFixed, types::U56F8};
+#[cfg(feature = "half_duplex")]
+use heapless::Deque;
 use keyberon::layout::Event as KBEvent;
-use utils::protocol::{Hardware, SideProtocol};
+use utils::key_matrix::KeyMatrix;
+#[cfg(feature = "half_duplex")]
+use utils::protocol::HalfDuplex;
+use utils::protocol::{
+    Handshake, Hardware, LinkState, SideProtocol, CAP_CUMULATIVE_ACK, CAP_LINK_SPEED, LINK_SPEEDS,
+};
 use utils::serde::Event;
 
-pub const USART_SPEED: u64 = 57600;
-
 /// Number of events in the channel to the other half of the keyboard
 const NB_EVENTS: usize = 64;
 /// Channel to send `utils::serde::event` events to the layout handler
 pub static SIDE_CHANNEL: Channel<CriticalSectionRawMutex, Event, NB_EVENTS> = Channel::new();
 
+/// Number of commands in the side command channel
+const NB_CMD: usize = 4;
+/// Channel to send commands to the side communication handler
+pub static SIDE_CMD_CHANNEL: Channel<CriticalSectionRawMutex, SideCommand, NB_CMD> = Channel::new();
+
+/// Commands to the side communication handler
+#[derive(Debug, defmt::Format)]
+pub enum SideCommand {
+    /// Log the protocol statistics
+    DumpStats,
+}
+
+/// Period at which the protocol timeouts are checked
+const TICK_PERIOD: Duration = Duration::from_millis(5);
+
+/// Maximum number of words sent whose echo is awaited
+#[cfg(feature = "half_duplex")]
+const NB_ECHOES: usize = 16;
+/// Depth of the FIFO of the sending state machine, joined for TX only
+#[cfg(feature = "half_duplex")]
+const TX_FIFO_DEPTH: u64 = 8;
+/// Delay after which an echo not read back is considered lost, on top of the
+/// time to send the word, to leave time for the receiving task to run
+#[cfg(feature = "half_duplex")]
+const ECHO_MARGIN: Duration = Duration::from_millis(10);
+
 const TX: usize = 0;
 const RX: usize = 1;
 
@@ -32,6 +71,8 @@ struct SidesComms<'a, W: Sized + Hardware> {
     protocol: SideProtocol<W>,
     /// State machine to receive events
     rx_sm: SmRx<'a>,
+    /// Speed of the receiving state machine, in bauds
+    rx_speed: u32,
     /// Status LED
     status_led: &'a mut Output<'static>,
 }
@@ -39,15 +80,98 @@ struct SidesComms<'a, W: Sized + Hardware> {
 struct SenderHw<'a> {
     /// State machine to send events
     tx: SmTx<'a>,
+    /// Speed of the sending state machine, in bauds
+    speed: u32,
     // error state
     on_error: bool,
+    /// Whether the other side runs an incompatible firmware
+    incompatible: bool,
+    /// Keys of the other side currently pressed
+    remote_keys: KeyMatrix,
+    /// Words sent that the receiving state machine will read back from the
+    /// shared wire, with the time after which they are known to be lost
+    #[cfg(feature = "half_duplex")]
+    echoes: Deque<(u32, Instant), NB_ECHOES>,
 }
 
 impl<'a> SenderHw<'a> {
     pub fn new(tx: SmTx<'a>) -> Self {
         Self {
             tx,
+            speed: LINK_SPEEDS[0],
             on_error: false,
+            incompatible: false,
+            remote_keys: KeyMatrix::new(),
+            #[cfg(feature = "half_duplex")]
+            echoes: Deque::new(),
+        }
+    }
+
+    /// Seed the random number generators of both sides with the same value
+    async fn share_seed(&mut self) {
+        // The time at which the link comes up is random enough
+        let seed = rosc_freq() ^ Instant::now().as_ticks() as u32;
+        defmt::info!("Sharing seed {}", seed);
+        // Do not wait on the side channel: it is drained by the caller
+        if SIDE_CHANNEL.try_send(Event::SeedRng32(seed)).is_err() {
+            defmt::error!("Side channel is full");
+            return;
+        }
+        if ANIM_CHANNEL.is_full() {
+            defmt::error!("Anim channel is full");
+        }
+        ANIM_CHANNEL.send(AnimCommand::Seed(seed)).await;
+    }
+
+    /// Release all the keys of the other side still pressed
+    async fn release_remote_keys(&mut self) {
+        self.apply_remote_keys(KeyMatrix::new()).await;
+    }
+
+    /// Turn the keys of the other side believed to be pressed into @keys
+    /// Returns the number of keys pressed or released
+    async fn apply_remote_keys(&mut self, keys: KeyMatrix) -> usize {
+        let mut nb = 0;
+        for event in self.remote_keys.diff(&keys) {
+            let event = match event {
+                Event::Press(i, j) => KBEvent::Press(i, j),
+                Event::Release(i, j) => KBEvent::Release(i, j),
+                _ => continue,
+            };
+            if LAYOUT_CHANNEL.is_full() {
+                defmt::error!("Layout channel is full");
+            }
+            LAYOUT_CHANNEL.send(event).await;
+            nb += 1;
+        }
+        self.remote_keys = keys;
+        nb
+    }
+}
+
+#[cfg(feature = "half_duplex")]
+impl SenderHw<'_> {
+    /// Whether a word read from the shared wire was sent by this side
+    /// Echoes are told apart by their content rather than counted, so that an
+    /// echo lost, for instance when the link speed changes, is not mistaken
+    /// for a word of the other side.
+    fn is_echo(&mut self, msg: u32) -> bool {
+        let now = Instant::now();
+        while let Some(&(_, deadline)) = self.echoes.front() {
+            if deadline >= now {
+                break;
+            }
+            self.echoes.pop_front();
+        }
+        // Echoes before the matching one were garbled or lost
+        match self.echoes.iter().position(|&(word, _)| word == msg) {
+            Some(idx) => {
+                for _ in 0..=idx {
+                    self.echoes.pop_front();
+                }
+                true
+            }
+            None => false,
         }
     }
 }
@@ -55,6 +179,17 @@ impl<'a> SenderHw<'a> {
 impl Hardware for SenderHw<'_> {
     async fn send(&mut self, msg: u32) {
         self.tx.tx().wait_push(msg).await;
+        #[cfg(feature = "half_duplex")]
+        {
+            // The word goes out after the ones already in the FIFO
+            let deadline = Instant::now()
+                + Duration::from_micros(word_duration_us(self.speed) * (TX_FIFO_DEPTH + 1))
+                + ECHO_MARGIN;
+            if self.echoes.is_full() {
+                self.echoes.pop_front();
+            }
+            let _ = self.echoes.push_back((msg, deadline));
+        }
     }
 
     async fn wait_a_bit(&mut self) {
@@ -66,12 +201,14 @@ impl Hardware for SenderHw<'_> {
         match event {
             Event::Noop => {}
             Event::Press(i, j) => {
+                self.remote_keys.set(i, j, true);
                 if LAYOUT_CHANNEL.is_full() {
                     defmt::error!("Layout channel is full");
                 }
                 LAYOUT_CHANNEL.send(KBEvent::Press(i, j)).await;
             }
             Event::Release(i, j) => {
+                self.remote_keys.set(i, j, false);
                 if LAYOUT_CHANNEL.is_full() {
                     defmt::error!("Layout channel is full");
                 }
@@ -89,15 +226,45 @@ impl Hardware for SenderHw<'_> {
                 }
                 ANIM_CHANNEL.send(AnimCommand::ChangeLayer(layer)).await;
             }
+            Event::KeySnapshot(keys) => {
+                let nb = self.apply_remote_keys(keys).await;
+                if nb > 0 {
+                    defmt::warn!("Snapshot fixed {} keys of the other side", nb);
+                }
+            }
+            Event::MouseMove(mouse_move) => {
+                if MOUSE_MOVE_CHANNEL.is_full() {
+                    defmt::error!("Mouse move channel is full");
+                }
+                MOUSE_MOVE_CHANNEL.send(mouse_move).await;
+            }
             Event::SeedRng(seed) => {
-                todo!("Seed random {}", seed);
+                if ANIM_CHANNEL.is_full() {
+                    defmt::error!("Anim channel is full");
+                }
+                ANIM_CHANNEL.send(AnimCommand::Seed(seed as u32)).await;
+            }
+            Event::SeedRng32(seed) => {
+                if ANIM_CHANNEL.is_full() {
+                    defmt::error!("Anim channel is full");
+                }
+                ANIM_CHANNEL.send(AnimCommand::Seed(seed)).await;
             }
+            Event::SensorCommand(code) => match SensorCommand::from_u8(code) {
+                Some(cmd) => cmd.send_local(),
+                None => defmt::warn!("Unknown sensor command {}", code),
+            },
             _ => {
                 defmt::warn!("Unhandled event {:?}", defmt::Debug2Format(&event));
             }
         }
     }
 
+    /// Current time, in milliseconds
+    fn now(&self) -> u64 {
+        Instant::now().as_millis()
+    }
+
     // Set error state
     async fn set_error_state(&mut self, error: bool) {
         if error && !self.on_error {
@@ -107,7 +274,7 @@ impl Hardware for SenderHw<'_> {
             }
             ANIM_CHANNEL.send(AnimCommand::Error).await;
         }
-        if !error && self.on_error {
+        if !error && self.on_error && !self.incompatible {
             self.on_error = false;
             if ANIM_CHANNEL.is_full() {
                 defmt::error!("Anim channel is full");
@@ -115,41 +282,199 @@ impl Hardware for SenderHw<'_> {
             ANIM_CHANNEL.send(AnimCommand::Fixed).await;
         }
     }
+
+    /// The state of the link with the other side changed
+    async fn on_link_state(&mut self, state: LinkState) {
+        defmt::info!("Link state: {:?}", state);
+        if state == LinkState::Down {
+            // Do not leave keys of the other side stuck
+            self.release_remote_keys().await;
+            self.set_error_state(true).await;
+        }
+    }
+
+    /// The handshake with the other side completed
+    async fn on_handshake(&mut self, handshake: Handshake) {
+        defmt::info!("Handshake: {:?}", handshake);
+        // The error state is kept until the other side is flashed
+        self.incompatible = handshake == Handshake::Incompatible;
+        if self.incompatible {
+            self.release_remote_keys().await;
+            self.set_error_state(true).await;
+        } else {
+            self.set_error_state(false).await;
+            if is_host() {
+                self.share_seed().await;
+            }
+        }
+    }
+
+    /// The other side restarted: send it the state it lost
+    async fn on_peer_reset(&mut self) {
+        defmt::warn!("The other side restarted");
+        // Its keys were released when it restarted
+        self.release_remote_keys().await;
+        if ANIM_CHANNEL.is_full() {
+            defmt::error!("Anim channel is full");
+        }
+        ANIM_CHANNEL.send(AnimCommand::Resync).await;
+        RESYNC_KEYS.signal(());
+    }
+
+    /// Switch the sending state machine to the given speed
+    async fn set_link_speed(&mut self, speed: u32) {
+        defmt::info!("Link speed: {} bauds", speed);
+        // Let the words already pushed go out at the previous speed
+        while !self.tx.tx().empty() {
+            Timer::after_micros(100).await;
+        }
+        Timer::after_micros(word_duration_us(self.speed)).await;
+        self.tx.set_clock_divider(pio_freq(speed));
+        self.tx.clkdiv_restart();
+        self.speed = speed;
+    }
 }
 
-impl<'a, W: Sized + Hardware> SidesComms<'a, W> {
+impl<'a> SidesComms<'a, SenderHw<'a>> {
     /// Create a new event buffer
     pub fn new(
         name: &'static str,
-        sender_hw: W,
+        sender_hw: SenderHw<'a>,
         rx_sm: SmRx<'a>,
         status_led: &'a mut Output<'static>,
     ) -> Self {
+        let mut protocol = SideProtocol::new(sender_hw, name);
+        protocol.enable_handshake(build_hash());
+        protocol.set_capabilities(CAP_CUMULATIVE_ACK | CAP_LINK_SPEED);
         Self {
-            protocol: SideProtocol::new(sender_hw, name),
+            protocol,
             rx_sm,
+            rx_speed: LINK_SPEEDS[0],
             status_led,
         }
     }
 
+    /// Switch the receiving state machine to the speed of the link
+    /// negotiated by the protocol
+    fn sync_rx_speed(&mut self) {
+        let speed = self.protocol.link_speed();
+        if speed == self.rx_speed {
+            return;
+        }
+        self.rx_speed = speed;
+        self.rx_sm.set_enable(false);
+        self.rx_sm.set_clock_divider(pio_freq(speed));
+        // Words received at the previous speed are garbage.  The echoes
+        // dropped here are forgotten once overdue.
+        self.rx_sm.clear_fifos();
+        self.rx_sm.restart();
+        self.rx_sm.clkdiv_restart();
+        self.rx_sm.set_enable(true);
+    }
+
     /// Run the communication between the two sides
     pub async fn run(&mut self) {
+        let mut ticker = Ticker::every(TICK_PERIOD);
+        // The other side may still have the counters from before this side
+        // restarted
+        if let Err(e) = self.protocol.announce_reset().await {
+            defmt::error!("Unable to announce the reset: {:?}", e);
+        }
         // Wait for the other side to boot
         loop {
-            match select(SIDE_CHANNEL.receive(), self.rx_sm.rx().wait_pull()).await {
-                Either::First(event) => {
-                    self.protocol.queue_event(event).await;
+            self.sync_rx_speed();
+            if self.protocol.is_queue_full() {
+                // Apply backpressure: let the events pile up in the side
+                // channel until the other side has caught up
+                match select3(
+                    self.rx_sm.rx().wait_pull(),
+                    ticker.next(),
+                    SIDE_CMD_CHANNEL.receive(),
+                )
+                .await
+                {
+                    Either3::First(x) => self.on_receive(x).await,
+                    Either3::Second(_) => self.protocol.tick().await,
+                    Either3::Third(cmd) => self.on_command(cmd),
+                }
+                continue;
+            }
+            match select4(
+                SIDE_CHANNEL.receive(),
+                self.rx_sm.rx().wait_pull(),
+                ticker.next(),
+                SIDE_CMD_CHANNEL.receive(),
+            )
+            .await
+            {
+                Either4::First(event) => {
+                    if let Err(e) = self.protocol.queue_event(event).await {
+                        defmt::error!("Unable to queue event {:?}: {:?}", event, e);
+                    }
+                }
+                Either4::Second(x) => {
+                    self.on_receive(x).await;
                 }
-                Either::Second(x) => {
-                    self.status_led.set_low();
-                    self.protocol.receive(x).await;
-                    self.status_led.set_high();
+                Either4::Third(_) => {
+                    self.protocol.tick().await;
                 }
+                Either4::Fourth(cmd) => {
+                    self.on_command(cmd);
+                }
+            }
+        }
+    }
+
+    /// Handle a command from the other tasks
+    fn on_command(&mut self, cmd: SideCommand) {
+        match cmd {
+            SideCommand::DumpStats => {
+                defmt::info!(
+                    "Link {:?}, {:?}, {:?}",
+                    self.protocol.link_state(),
+                    self.protocol.stats(),
+                    self.protocol.window_stats()
+                );
+                #[cfg(feature = "journal")]
+                self.dump_journal();
             }
         }
     }
+
+    /// Log the last words exchanged with the other side, one per line
+    /// `utils::journal::write_mermaid` turns the log into a sequence diagram.
+    #[cfg(feature = "journal")]
+    fn dump_journal(&self) {
+        let journal = self.protocol.journal();
+        defmt::info!(
+            "Journal of the {} side, {} words",
+            self.protocol.name(),
+            journal.len()
+        );
+        for entry in journal.iter() {
+            defmt::info!(
+                "journal {} {} {=u32:08x} {:?}",
+                entry.at,
+                entry.direction.as_str(),
+                entry.msg,
+                entry.decoded
+            );
+        }
+    }
+
+    /// Handle a message received from the other side
+    async fn on_receive(&mut self, msg: u32) {
+        #[cfg(feature = "half_duplex")]
+        if self.protocol.hw_mut().is_echo(msg) {
+            return;
+        }
+        self.status_led.set_low();
+        self.protocol.receive(msg).await;
+        self.status_led.set_high();
+    }
 }
 
+#[cfg(not(feature = "half_duplex"))]
 pub async fn full_duplex_comm<'a>(
     mut pio_common: PioCommon<'a>,
     sm0: SmTx<'a>,
@@ -180,13 +505,63 @@ pub async fn full_duplex_comm<'a>(
     let sender_hw = SenderHw::new(tx_sm);
     let mut sides_comms: SidesComms<'_, SenderHw<'_>> =
         SidesComms::new(name, sender_hw, rx_sm, status_led);
+    if is_right {
+        sides_comms.protocol.lead_link_speed();
+    }
     sides_comms.run().await;
 }
 
-fn pio_freq() -> fixed::FixedU32<fixed::types::extra::U8> {
-    (U56F8::from_num(clk_sys_freq()) / (8 * USART_SPEED)).to_fixed()
+/// Communicate with the other side over the single wire of unmodified boards
+/// Both sides take turns to drive it, the right side polling the left one.
+#[cfg(feature = "half_duplex")]
+pub async fn half_duplex_comm<'a>(
+    mut pio_common: PioCommon<'a>,
+    sm0: SmTx<'a>,
+    sm1: SmRx<'a>,
+    gpio_pin_1: PIN_1,
+    status_led: &mut Output<'static>,
+    is_right: bool,
+) {
+    let mut pin = pio_common.make_pio_pin(gpio_pin_1);
+    // Keep the wire high while neither side drives it
+    pin.set_pull(Pull::Up);
+    // Ensure everything is stable before starting the communication
+    Timer::after_secs(6).await;
+
+    let tx_sm = task_tx_hd(&mut pio_common, sm0, &mut pin);
+    let rx_sm = task_rx(&mut pio_common, sm1, &mut pin);
+
+    let name = if is_right { "Right" } else { "Left" };
+    let sender_hw = SenderHw::new(tx_sm);
+    let mut sides_comms: SidesComms<'_, SenderHw<'_>> =
+        SidesComms::new(name, sender_hw, rx_sm, status_led);
+    if is_right {
+        sides_comms.protocol.lead_link_speed();
+        sides_comms.protocol.set_half_duplex(HalfDuplex::Leader);
+    } else {
+        sides_comms.protocol.set_half_duplex(HalfDuplex::Follower);
+    }
+    sides_comms.run().await;
+}
+
+/// Hash of the firmware build, computed by the build script
+fn build_hash() -> u32 {
+    u32::from_str_radix(env!("BUILD_HASH"), 16).unwrap_or(0)
+}
+
+/// Clock divider of the state machines for the given speed, in bauds
+/// Each bit takes 8 cycles.
+fn pio_freq(speed: u32) -> fixed::FixedU32<fixed::types::extra::U8> {
+    (U56F8::from_num(clk_sys_freq()) / (8 * speed as u64)).to_fixed()
 }
 
+/// Time to send a word at the given speed, in µs
+/// A word is a start bit, 32 bits of data and a stop bit.
+fn word_duration_us(speed: u32) -> u64 {
+    34 * 1_000_000 / speed as u64 + 1
+}
+
+#[cfg(not(feature = "half_duplex"))]
 fn task_tx<'a>(
     common: &mut PioCommon<'a>,
     mut sm_tx: SmTx<'a>,
@@ -204,7 +579,33 @@ fn task_tx<'a>(
     cfg.shift_out.direction = ShiftDirection::Right;
     cfg.shift_out.threshold = 32;
     cfg.fifo_join = FifoJoin::TxOnly;
-    cfg.clock_divider = pio_freq();
+    cfg.clock_divider = pio_freq(LINK_SPEEDS[0]);
+    sm_tx.set_config(&cfg);
+    sm_tx.set_enable(true);
+
+    sm_tx
+}
+
+/// Sending state machine driving the shared wire only while sending a word
+#[cfg(feature = "half_duplex")]
+fn task_tx_hd<'a>(
+    common: &mut PioCommon<'a>,
+    mut sm_tx: SmTx<'a>,
+    tx_pin: &mut PioPin<'a>,
+) -> SmTx<'a> {
+    let tx_prog = pio_proc::pio_file!("src/tx_hd.pio");
+    sm_tx.set_pins(Level::High, &[tx_pin]);
+    sm_tx.set_pin_dirs(Direction::In, &[tx_pin]);
+
+    let mut cfg = embassy_rp::pio::Config::default();
+    cfg.set_out_pins(&[tx_pin]);
+    cfg.set_set_pins(&[tx_pin]);
+    cfg.use_program(&common.load_program(&tx_prog.program), &[tx_pin]);
+    cfg.shift_out.auto_fill = false;
+    cfg.shift_out.direction = ShiftDirection::Right;
+    cfg.shift_out.threshold = 32;
+    cfg.fifo_join = FifoJoin::TxOnly;
+    cfg.clock_divider = pio_freq(LINK_SPEEDS[0]);
     sm_tx.set_config(&cfg);
     sm_tx.set_enable(true);
 
@@ -226,7 +627,7 @@ fn task_rx<'a>(
     cfg.set_jmp_pin(rx_pin);
     sm_rx.set_pin_dirs(Direction::In, &[rx_pin]);
 
-    cfg.clock_divider = pio_freq();
+    cfg.clock_divider = pio_freq(LINK_SPEEDS[0]);
     cfg.shift_in.auto_fill = false;
     cfg.shift_in.direction = ShiftDirection::Right;
     cfg.shift_in.threshold = 32;
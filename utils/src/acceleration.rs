@@ -0,0 +1,255 @@
+//! Acceleration of the pointer
+//!
+//! The deltas of the sensor are scaled by a gain that depends on the speed of
+//! the ball, in counts per report.  Gains are in fixed point, `UNIT` being a
+//! gain of 1.  The fractions of counts left after scaling are kept for the
+//! next report, so that slow motions are not lost to rounding.
+
+use crate::mouse_move::MouseMove;
+
+/// Gain of 1, in fixed point
+pub const UNIT: i32 = 256;
+
+/// Curve giving the gain for a speed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Profile {
+    /// Same gain at every speed
+    Linear {
+        /// Gain
+        gain: i32,
+    },
+    /// `gain = base + (speed / scale) ^ exponent`, capped at @max
+    Power {
+        /// Gain at rest
+        base: i32,
+        /// Speed at which the gain has grown by 1
+        scale: u32,
+        /// Exponent of the curve
+        exponent: u32,
+        /// Highest gain
+        max: i32,
+    },
+    /// S-shaped curve from @min at rest to @max at high speed
+    Sigmoid {
+        /// Gain at rest
+        min: i32,
+        /// Gain at high speed
+        max: i32,
+        /// Speed at which the gain is halfway between @min and @max
+        mid: u32,
+        /// Speed range over which most of the change happens
+        width: u32,
+    },
+    /// Gains at given speeds, sorted by speed, interpolated in between
+    Table(&'static [(u32, i32)]),
+}
+
+impl Profile {
+    /// Gain at the given speed, in counts per report
+    pub fn gain(&self, speed: u32) -> i32 {
+        match *self {
+            Profile::Linear { gain } => gain,
+            Profile::Power {
+                base,
+                scale,
+                exponent,
+                max,
+            } => {
+                // (speed / scale) ^ exponent, in fixed point
+                let ratio = speed as i64 * UNIT as i64 / scale.max(1) as i64;
+                let mut growth = UNIT as i64;
+                for _ in 0..exponent {
+                    growth = (growth * ratio / UNIT as i64).min(max as i64);
+                }
+                (base as i64 + growth).min(max as i64) as i32
+            }
+            Profile::Sigmoid {
+                min,
+                max,
+                mid,
+                width,
+            } => {
+                // x / (1 + |x|) goes from -1 to 1, like a logistic curve
+                // without needing an exponential
+                let x = (speed as i64 - mid as i64) * UNIT as i64 / width.max(1) as i64;
+                let s = UNIT as i64 / 2 + x * UNIT as i64 / (2 * (UNIT as i64 + x.abs()));
+                (min as i64 + (max - min) as i64 * s / UNIT as i64) as i32
+            }
+            Profile::Table(points) => {
+                let Some(&(first_speed, first_gain)) = points.first() else {
+                    return UNIT;
+                };
+                if speed <= first_speed {
+                    return first_gain;
+                }
+                for pair in points.windows(2) {
+                    let ((s0, g0), (s1, g1)) = (pair[0], pair[1]);
+                    if speed <= s1 {
+                        let span = (s1 - s0).max(1) as i64;
+                        let offset = (speed - s0) as i64;
+                        return (g0 as i64 + (g1 - g0) as i64 * offset / span) as i32;
+                    }
+                }
+                points[points.len() - 1].1
+            }
+        }
+    }
+}
+
+/// Length of a movement, in counts
+fn speed(dx: i16, dy: i16) -> u32 {
+    let (x, y) = (dx.unsigned_abs() as u32, dy.unsigned_abs() as u32);
+    let square = x * x + y * y;
+    // Integer square root, by Newton's method
+    let mut root = square;
+    let mut next = root.div_ceil(2);
+    while next < root {
+        root = next;
+        next = (root + square / root) / 2;
+    }
+    root
+}
+
+/// Applies a profile to the movements of the sensor
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Accelerator {
+    /// Curve in use
+    profile: Profile,
+    /// Fractions of counts not yet sent, in fixed point
+    remainder: (i32, i32),
+}
+
+impl Accelerator {
+    /// Create an accelerator with the given profile
+    pub const fn new(profile: Profile) -> Self {
+        Self {
+            profile,
+            remainder: (0, 0),
+        }
+    }
+
+    /// Curve in use
+    pub fn profile(&self) -> Profile {
+        self.profile
+    }
+
+    /// Switch to another curve
+    pub fn set_profile(&mut self, profile: Profile) {
+        self.profile = profile;
+        self.remainder = (0, 0);
+    }
+
+    /// Scale a movement of the sensor
+    pub fn apply(&mut self, MouseMove { dx, dy }: MouseMove) -> MouseMove {
+        let gain = self.profile.gain(speed(dx, dy));
+        let x = dx as i32 * gain + self.remainder.0;
+        let y = dy as i32 * gain + self.remainder.1;
+        // Rounds toward 0, so that the remainders are as small going either way
+        self.remainder = (x % UNIT, y % UNIT);
+        MouseMove::new(clamp(x / UNIT), clamp(y / UNIT))
+    }
+}
+
+/// Saturate to the range of a delta
+fn clamp(v: i32) -> i16 {
+    v.clamp(i16::MIN as i32, i16::MAX as i32) as i16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TABLE: [(u32, i32); 3] = [(2, UNIT), (10, 2 * UNIT), (20, 4 * UNIT)];
+
+    #[test]
+    fn test_speed() {
+        assert_eq!(speed(0, 0), 0);
+        assert_eq!(speed(3, -4), 5);
+        assert_eq!(speed(-1, 0), 1);
+        assert_eq!(speed(10, 10), 14);
+        assert_eq!(speed(i16::MIN, i16::MIN), 46_340);
+    }
+
+    #[test]
+    fn test_linear() {
+        let profile = Profile::Linear { gain: UNIT * 3 / 2 };
+        assert_eq!(profile.gain(0), UNIT * 3 / 2);
+        assert_eq!(profile.gain(1000), UNIT * 3 / 2);
+    }
+
+    #[test]
+    fn test_power() {
+        let profile = Profile::Power {
+            base: UNIT,
+            scale: 10,
+            exponent: 2,
+            max: 4 * UNIT,
+        };
+        assert_eq!(profile.gain(0), UNIT);
+        assert_eq!(profile.gain(5), UNIT + UNIT / 4);
+        assert_eq!(profile.gain(10), 2 * UNIT);
+        assert_eq!(profile.gain(1000), 4 * UNIT);
+        assert_eq!(profile.gain(u32::MAX), 4 * UNIT);
+    }
+
+    #[test]
+    fn test_sigmoid() {
+        let profile = Profile::Sigmoid {
+            min: UNIT,
+            max: 3 * UNIT,
+            mid: 20,
+            width: 5,
+        };
+        assert_eq!(profile.gain(20), 2 * UNIT);
+        let mut previous = 0;
+        for speed in 0..100 {
+            let gain = profile.gain(speed);
+            assert!(gain >= previous);
+            assert!((UNIT..=3 * UNIT).contains(&gain));
+            previous = gain;
+        }
+        assert!(profile.gain(0) < UNIT + UNIT / 4);
+        assert!(profile.gain(100) > 3 * UNIT - UNIT / 4);
+    }
+
+    #[test]
+    fn test_table() {
+        let profile = Profile::Table(&TABLE);
+        assert_eq!(profile.gain(0), UNIT);
+        assert_eq!(profile.gain(2), UNIT);
+        assert_eq!(profile.gain(6), UNIT * 3 / 2);
+        assert_eq!(profile.gain(10), 2 * UNIT);
+        assert_eq!(profile.gain(15), 3 * UNIT);
+        assert_eq!(profile.gain(50), 4 * UNIT);
+        assert_eq!(Profile::Table(&[]).gain(10), UNIT);
+    }
+
+    #[test]
+    fn test_remainder() {
+        // A third of a count per report adds up
+        let mut accel = Accelerator::new(Profile::Linear { gain: UNIT / 3 + 1 });
+        let moves: Vec<_> = (0..6).map(|_| accel.apply(MouseMove::new(1, -1))).collect();
+        assert_eq!(moves.iter().map(|m| m.dx).sum::<i16>(), 2);
+        assert_eq!(moves.iter().map(|m| m.dy).sum::<i16>(), -2);
+        assert_eq!(moves[0], MouseMove::new(0, 0));
+
+        // Switching profiles drops the remainders
+        accel.apply(MouseMove::new(1, 1));
+        accel.set_profile(Profile::Linear { gain: UNIT / 3 + 1 });
+        assert_eq!(accel.apply(MouseMove::new(2, 2)), MouseMove::new(0, 0));
+    }
+
+    #[test]
+    fn test_acceleration() {
+        let mut accel = Accelerator::new(Profile::Table(&TABLE));
+        assert_eq!(accel.apply(MouseMove::new(1, 0)), MouseMove::new(1, 0));
+        assert_eq!(accel.apply(MouseMove::new(0, -10)), MouseMove::new(0, -20));
+        assert_eq!(accel.apply(MouseMove::new(12, 16)), MouseMove::new(48, 64));
+        assert_eq!(
+            accel.apply(MouseMove::new(i16::MAX, 0)),
+            MouseMove::new(i16::MAX, 0)
+        );
+    }
+}
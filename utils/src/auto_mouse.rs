@@ -0,0 +1,200 @@
+//! Automatic mouse layer
+//!
+//! Moving the ball activates a layer with the mouse buttons.  The layer is
+//! turned off once the ball has not moved for a while, no mouse key being
+//! held, or as soon as a key which is not a mouse key is pressed.
+
+use crate::mouse_move::MouseMove;
+
+/// When to activate and deactivate the mouse layer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct AutoMouseConfig {
+    /// Counts the ball has to travel to activate the layer, so that bumping
+    /// the ball does not
+    pub threshold: u32,
+    /// Time without motion after which the layer is turned off, in ms
+    pub timeout_ms: u64,
+}
+
+/// Change of the mouse layer to apply on the layout
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Transition {
+    /// Nothing to do
+    None,
+    /// Turn the mouse layer on
+    Activate,
+    /// Turn the mouse layer off
+    Deactivate,
+}
+
+/// State of the automatic mouse layer
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct AutoMouse {
+    /// Threshold and timeout
+    config: AutoMouseConfig,
+    /// Whether the mouse layer is on
+    active: bool,
+    /// Counts travelled since the ball started moving
+    travel: u32,
+    /// Time of the last motion of the ball or mouse key event, in ms
+    last_ms: u64,
+    /// Number of mouse keys held
+    held: u8,
+}
+
+impl AutoMouse {
+    /// Create the state, the mouse layer being off
+    pub const fn new(config: AutoMouseConfig) -> Self {
+        Self {
+            config,
+            active: false,
+            travel: 0,
+            last_ms: 0,
+            held: 0,
+        }
+    }
+
+    /// Whether the mouse layer is on
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// On a motion of the ball, at @now_ms
+    pub fn on_motion(&mut self, MouseMove { dx, dy }: MouseMove, now_ms: u64) -> Transition {
+        if dx == 0 && dy == 0 {
+            return Transition::None;
+        }
+        if now_ms.saturating_sub(self.last_ms) >= self.config.timeout_ms {
+            self.travel = 0;
+        }
+        self.last_ms = now_ms;
+        if self.active {
+            return Transition::None;
+        }
+        self.travel = self
+            .travel
+            .saturating_add(dx.unsigned_abs() as u32 + dy.unsigned_abs() as u32);
+        if self.travel < self.config.threshold {
+            return Transition::None;
+        }
+        self.active = true;
+        Transition::Activate
+    }
+
+    /// On a key being pressed or released, at @now_ms
+    /// @is_mouse_key tells whether the key is a mouse button or another key
+    /// of the mouse layer which keeps it on.
+    pub fn on_key(&mut self, is_mouse_key: bool, is_pressed: bool, now_ms: u64) -> Transition {
+        if !self.active {
+            return Transition::None;
+        }
+        if is_mouse_key {
+            self.held = if is_pressed {
+                self.held.saturating_add(1)
+            } else {
+                self.held.saturating_sub(1)
+            };
+            self.last_ms = now_ms;
+            return Transition::None;
+        }
+        if !is_pressed {
+            return Transition::None;
+        }
+        self.deactivate()
+    }
+
+    /// Turn the mouse layer off after the timeout, at @now_ms
+    pub fn tick(&mut self, now_ms: u64) -> Transition {
+        if !self.active
+            || self.held > 0
+            || now_ms.saturating_sub(self.last_ms) < self.config.timeout_ms
+        {
+            return Transition::None;
+        }
+        self.deactivate()
+    }
+
+    /// Turn the mouse layer off
+    fn deactivate(&mut self) -> Transition {
+        self.active = false;
+        self.travel = 0;
+        self.held = 0;
+        Transition::Deactivate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: AutoMouseConfig = AutoMouseConfig {
+        threshold: 10,
+        timeout_ms: 500,
+    };
+
+    fn motion(dx: i16, dy: i16) -> MouseMove {
+        MouseMove::new(dx, dy)
+    }
+
+    #[test]
+    fn test_threshold() {
+        let mut am = AutoMouse::new(CONFIG);
+        assert_eq!(am.on_motion(motion(0, 0), 0), Transition::None);
+        assert_eq!(am.on_motion(motion(3, -2), 10), Transition::None);
+        assert_eq!(am.on_motion(motion(-2, 2), 20), Transition::None);
+        assert!(!am.is_active());
+        assert_eq!(am.on_motion(motion(1, 0), 30), Transition::Activate);
+        assert!(am.is_active());
+        assert_eq!(am.on_motion(motion(5, 5), 40), Transition::None);
+
+        // Bumps far apart in time do not add up
+        let mut am = AutoMouse::new(CONFIG);
+        assert_eq!(am.on_motion(motion(6, 0), 1000), Transition::None);
+        assert_eq!(am.on_motion(motion(6, 0), 1600), Transition::None);
+        assert_eq!(am.on_motion(motion(6, 0), 1700), Transition::Activate);
+    }
+
+    #[test]
+    fn test_timeout() {
+        let mut am = AutoMouse::new(CONFIG);
+        assert_eq!(am.on_motion(motion(20, 0), 1000), Transition::Activate);
+        assert_eq!(am.tick(1400), Transition::None);
+        // Moving the ball keeps the layer on
+        assert_eq!(am.on_motion(motion(1, 0), 1400), Transition::None);
+        assert_eq!(am.tick(1899), Transition::None);
+        assert_eq!(am.tick(1900), Transition::Deactivate);
+        assert!(!am.is_active());
+        assert_eq!(am.tick(3000), Transition::None);
+    }
+
+    #[test]
+    fn test_mouse_keys() {
+        let mut am = AutoMouse::new(CONFIG);
+        assert_eq!(am.on_motion(motion(20, 0), 0), Transition::Activate);
+        // Holding a button, to drag, keeps the layer on
+        assert_eq!(am.on_key(true, true, 100), Transition::None);
+        assert_eq!(am.tick(2000), Transition::None);
+        assert_eq!(am.on_key(true, false, 2000), Transition::None);
+        assert_eq!(am.tick(2499), Transition::None);
+        assert_eq!(am.tick(2500), Transition::Deactivate);
+    }
+
+    #[test]
+    fn test_other_keys() {
+        let mut am = AutoMouse::new(CONFIG);
+        // Nothing to turn off
+        assert_eq!(am.on_key(false, true, 0), Transition::None);
+        assert_eq!(am.on_motion(motion(0, -20), 10), Transition::Activate);
+        // Releasing a key pressed before does not matter
+        assert_eq!(am.on_key(false, false, 20), Transition::None);
+        assert_eq!(am.on_key(true, true, 30), Transition::None);
+        assert_eq!(am.on_key(false, true, 40), Transition::Deactivate);
+        assert!(!am.is_active());
+        // The mouse key held does not keep the next activation on
+        assert_eq!(am.on_motion(motion(0, -20), 50), Transition::Activate);
+        assert_eq!(am.tick(550), Transition::Deactivate);
+    }
+}
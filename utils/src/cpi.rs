@@ -0,0 +1,189 @@
+//! Resolution of the sensor
+//!
+//! The sensor runs at a normal CPI, changed in steps or cycled through a set
+//! of values.  While sniping, for precise pointing, it switches to a lower CPI
+//! taken from its own set, the normal one being restored afterwards.
+
+/// Lowest CPI of the sensor
+pub const MIN_CPI: u16 = 100;
+/// Highest CPI of the sensor
+pub const MAX_CPI: u16 = 12000;
+
+/// CPI values to cycle through
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CpiConfig {
+    /// Normal CPI values, in increasing order
+    pub normal: &'static [u16],
+    /// Sniping CPI values, in increasing order
+    pub sniping: &'static [u16],
+}
+
+/// CPI the sensor should run at
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Cpi {
+    /// Values to cycle through
+    config: CpiConfig,
+    /// Normal CPI
+    normal: u16,
+    /// Index of the sniping CPI in `config.sniping`
+    sniping_idx: usize,
+    /// Whether sniping is on
+    sniping: bool,
+}
+
+/// Keep @cpi in the range of the sensor
+fn clamp(cpi: u16) -> u16 {
+    cpi.clamp(MIN_CPI, MAX_CPI)
+}
+
+impl Cpi {
+    /// Start at the @normal CPI, sniping at the first value of its set
+    pub const fn new(config: CpiConfig, normal: u16) -> Self {
+        Self {
+            config,
+            normal,
+            sniping_idx: 0,
+            sniping: false,
+        }
+    }
+
+    /// CPI the sensor should run at
+    pub fn current(&self) -> u16 {
+        if self.sniping {
+            if let Some(&cpi) = self.config.sniping.get(self.sniping_idx) {
+                return clamp(cpi);
+            }
+        }
+        clamp(self.normal)
+    }
+
+    /// Whether sniping is on
+    pub fn is_sniping(&self) -> bool {
+        self.sniping
+    }
+
+    /// Start or stop sniping
+    pub fn set_sniping(&mut self, sniping: bool) -> u16 {
+        self.sniping = sniping;
+        self.current()
+    }
+
+    /// Change the normal CPI by @delta
+    pub fn step(&mut self, delta: i16) -> u16 {
+        self.normal = clamp(self.normal.saturating_add_signed(delta));
+        self.current()
+    }
+
+    /// Switch to the next normal CPI of the set, the first one after the last
+    pub fn next_normal(&mut self) -> u16 {
+        let set = self.config.normal;
+        if let Some(&first) = set.first() {
+            self.normal = set
+                .iter()
+                .copied()
+                .find(|&cpi| cpi > self.normal)
+                .unwrap_or(first);
+        }
+        self.current()
+    }
+
+    /// Switch to the previous normal CPI of the set, the last one before the
+    /// first
+    pub fn previous_normal(&mut self) -> u16 {
+        let set = self.config.normal;
+        if let Some(&last) = set.last() {
+            self.normal = set
+                .iter()
+                .copied()
+                .rev()
+                .find(|&cpi| cpi < self.normal)
+                .unwrap_or(last);
+        }
+        self.current()
+    }
+
+    /// Switch to the next sniping CPI of the set, the first one after the last
+    pub fn next_sniping(&mut self) -> u16 {
+        let len = self.config.sniping.len().max(1);
+        self.sniping_idx = (self.sniping_idx + 1) % len;
+        self.current()
+    }
+
+    /// Switch to the previous sniping CPI of the set, the last one before the
+    /// first
+    pub fn previous_sniping(&mut self) -> u16 {
+        let len = self.config.sniping.len().max(1);
+        self.sniping_idx = (self.sniping_idx + len - 1) % len;
+        self.current()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: CpiConfig = CpiConfig {
+        normal: &[400, 800, 1200, 1600],
+        sniping: &[100, 200, 300],
+    };
+
+    #[test]
+    fn test_sniping() {
+        let mut cpi = Cpi::new(CONFIG, 800);
+        assert_eq!(cpi.current(), 800);
+        assert_eq!(cpi.set_sniping(true), 100);
+        assert!(cpi.is_sniping());
+        // Changing the normal CPI while sniping only shows after
+        assert_eq!(cpi.step(100), 100);
+        assert_eq!(cpi.set_sniping(false), 900);
+        assert!(!cpi.is_sniping());
+    }
+
+    #[test]
+    fn test_cycle_normal() {
+        let mut cpi = Cpi::new(CONFIG, 800);
+        assert_eq!(cpi.next_normal(), 1200);
+        assert_eq!(cpi.next_normal(), 1600);
+        assert_eq!(cpi.next_normal(), 400);
+        assert_eq!(cpi.previous_normal(), 1600);
+        // Off the set after a step: back to the nearest values
+        assert_eq!(cpi.step(-300), 1300);
+        assert_eq!(cpi.previous_normal(), 1200);
+        assert_eq!(cpi.step(-100), 1100);
+        assert_eq!(cpi.next_normal(), 1200);
+    }
+
+    #[test]
+    fn test_cycle_sniping() {
+        let mut cpi = Cpi::new(CONFIG, 800);
+        // Cycling while not sniping picks the value for the next time
+        assert_eq!(cpi.next_sniping(), 800);
+        assert_eq!(cpi.set_sniping(true), 200);
+        assert_eq!(cpi.next_sniping(), 300);
+        assert_eq!(cpi.next_sniping(), 100);
+        assert_eq!(cpi.previous_sniping(), 300);
+        assert_eq!(cpi.previous_normal(), 300);
+        assert_eq!(cpi.set_sniping(false), 400);
+    }
+
+    #[test]
+    fn test_bounds() {
+        let mut cpi = Cpi::new(CONFIG, 200);
+        assert_eq!(cpi.step(-500), MIN_CPI);
+        assert_eq!(cpi.step(i16::MAX), MAX_CPI);
+        assert_eq!(cpi.step(i16::MAX), MAX_CPI);
+
+        let mut empty = Cpi::new(
+            CpiConfig {
+                normal: &[],
+                sniping: &[],
+            },
+            800,
+        );
+        assert_eq!(empty.next_normal(), 800);
+        assert_eq!(empty.previous_sniping(), 800);
+        assert_eq!(empty.set_sniping(true), 800);
+    }
+}
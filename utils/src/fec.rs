@@ -0,0 +1,239 @@
+//! Forward error correction of the messages
+//!
+//! The 16 bits of data of a message are protected by an extended BCH(32,16)
+//! code: 15 check bits from the generator polynomial, then a parity bit.  Its
+//! minimum distance of 8 allows correcting up to 2 flipped bits while still
+//! detecting up to 5.  A word garbled by a burst is however taken for a valid
+//! one about once in 124 times.  Unless the `fec-double` feature is enabled,
+//! only single flipped bits are corrected: up to 6 are detected, and a garbled
+//! word is taken for a valid one about once in 2000 times.
+//!
+//! See: https://en.wikipedia.org/wiki/BCH_code
+
+/// Generator polynomial of the BCH(31,16) code:
+/// x^15 + x^11 + x^10 + x^9 + x^8 + x^7 + x^5 + x^3 + x^2 + x + 1
+const GENERATOR: u32 = 0x8faf;
+/// Degree of the generator polynomial, that is the number of check bits
+const DEGREE: u32 = 15;
+
+/// Remainder of the division of a polynomial by the generator polynomial
+const fn remainder(mut poly: u32) -> u32 {
+    let mut bit = 31;
+    while bit >= DEGREE {
+        if poly & (1 << bit) != 0 {
+            poly ^= GENERATOR << (bit - DEGREE);
+        }
+        bit -= 1;
+    }
+    poly
+}
+
+/// Syndrome of a word, null for valid words
+/// Syndromes are linear: the syndrome of a corrupted word is the one of the
+/// bits flipped.
+const fn syndrome(word: u32) -> u16 {
+    let poly = ((word & 0xffff) << DEGREE) | ((word >> 16) & 0x7fff);
+    (remainder(poly) | ((word.count_ones() & 1) << DEGREE)) as u16
+}
+
+/// Syndromes of each single flipped bit
+const BIT_SYNDROMES: [u16; 32] = {
+    let mut syndromes = [0; 32];
+    let mut i = 0;
+    while i < 32 {
+        syndromes[i] = syndrome(1 << i);
+        i += 1;
+    }
+    syndromes
+};
+
+/// Encode 16 bits of data: they are kept in the lower bits, the check bits
+/// are in the upper bits
+pub const fn encode(data: u16) -> u32 {
+    let check = remainder((data as u32) << DEGREE);
+    let word = (data as u32) | (check << 16);
+    word | ((word.count_ones() & 1) << 31)
+}
+
+/// Most flipped bits corrected by `decode`
+#[cfg(not(feature = "fec-double"))]
+pub const MAX_CORRECTED: u32 = 1;
+/// Most flipped bits corrected by `decode`
+#[cfg(feature = "fec-double")]
+pub const MAX_CORRECTED: u32 = 2;
+
+/// Decode a word, correcting up to `MAX_CORRECTED` flipped bits
+/// Gives the 16 bits of data and the number of bits corrected, or `None` if
+/// more bits were flipped
+pub fn decode(word: u32) -> Option<(u16, u32)> {
+    decode_up_to(word, MAX_CORRECTED)
+}
+
+/// Decode a word, correcting up to @max flipped bits, 2 at most
+pub fn decode_up_to(word: u32, max: u32) -> Option<(u16, u32)> {
+    let s = syndrome(word);
+    if s == 0 {
+        return Some((word as u16, 0));
+    }
+    if max == 0 {
+        return None;
+    }
+    for (i, si) in BIT_SYNDROMES.iter().enumerate() {
+        if *si == s {
+            return Some(((word ^ (1 << i)) as u16, 1));
+        }
+    }
+    if max == 1 {
+        return None;
+    }
+    for (i, si) in BIT_SYNDROMES.iter().enumerate() {
+        for (j, sj) in BIT_SYNDROMES.iter().enumerate().skip(i + 1) {
+            if si ^ sj == s {
+                return Some(((word ^ (1 << i) ^ (1 << j)) as u16, 2));
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prng::XorShift32;
+
+    /// Flip `n` distinct random bits
+    fn flip_bits(word: u32, n: u32, prng: &mut XorShift32) -> u32 {
+        let mut errors = 0u32;
+        while errors.count_ones() < n {
+            errors |= 1 << (prng.random() % 32);
+        }
+        word ^ errors
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        for data in 0..=u16::MAX {
+            let word = encode(data);
+            assert_eq!(word as u16, data);
+            assert_eq!(decode(word), Some((data, 0)));
+        }
+    }
+
+    #[test]
+    fn test_minimum_distance() {
+        for data in 1..=u16::MAX {
+            assert!(encode(data).count_ones() >= 8, "0x{:04x}", data);
+        }
+    }
+
+    #[test]
+    fn test_correct_one_bit() {
+        let word = encode(0xbeef);
+        for i in 0..32 {
+            assert_eq!(decode_up_to(word ^ (1 << i), 1), Some((0xbeef, 1)));
+            assert_eq!(decode_up_to(word ^ (1 << i), 0), None);
+            for j in i + 1..32 {
+                assert_eq!(decode_up_to(word ^ (1 << i) ^ (1 << j), 1), None);
+            }
+        }
+    }
+
+    #[test]
+    fn test_correct_two_bits() {
+        let word = encode(0xbeef);
+        for i in 0..32 {
+            assert_eq!(decode_up_to(word ^ (1 << i), 2), Some((0xbeef, 1)));
+            for j in i + 1..32 {
+                assert_eq!(
+                    decode_up_to(word ^ (1 << i) ^ (1 << j), 2),
+                    Some((0xbeef, 2))
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode() {
+        let word = encode(0xbeef) ^ 0x8001;
+        assert_eq!(decode(word), decode_up_to(word, MAX_CORRECTED));
+    }
+
+    #[test]
+    fn test_detect_six_bits() {
+        let mut prng = XorShift32::new(0x5eed);
+        for _ in 0..100_000 {
+            let data = prng.random() as u16;
+            let n = 2 + prng.random() % 5;
+            let word = flip_bits(encode(data), n, &mut prng);
+            assert_eq!(decode_up_to(word, 1), None, "0x{:08x}, {} bits", word, n);
+        }
+    }
+
+    #[test]
+    fn test_detect_five_bits() {
+        let mut prng = XorShift32::new(0x5eed);
+        for _ in 0..100_000 {
+            let data = prng.random() as u16;
+            let n = 3 + prng.random() % 3;
+            let word = flip_bits(encode(data), n, &mut prng);
+            assert_eq!(decode_up_to(word, 2), None, "0x{:08x}, {} bits", word, n);
+        }
+    }
+
+    #[test]
+    fn test_garbled_words() {
+        // Words garbled by a burst are random: the ones close enough to a
+        // valid word to be corrected are accepted
+        let mut prng = XorShift32::new(0xb0b);
+        let nb_words = 1_000_000;
+        let (mut one_bit, mut two_bits) = (0, 0);
+        for _ in 0..nb_words {
+            let word = prng.random();
+            one_bit += decode_up_to(word, 1).is_some() as usize;
+            two_bits += decode_up_to(word, 2).is_some() as usize;
+        }
+        assert!(one_bit < nb_words / 1500, "{} accepted", one_bit);
+        assert!(two_bits < nb_words / 100, "{} accepted", two_bits);
+    }
+
+    #[test]
+    fn test_retransmits_avoided() {
+        // Count the words that need a retransmit, under random bit flips
+        let mut prng = XorShift32::new(42);
+        let nb_words = 100_000;
+        let bit_error_rate = 0.005;
+        let (mut crc_retransmits, mut one_bit_retransmits, mut two_bits_retransmits) = (0, 0, 0);
+        for _ in 0..nb_words {
+            let data = prng.random() as u16;
+            let mut errors = 0u32;
+            for bit in 0..32 {
+                if (prng.random() as f64 / u32::MAX as f64) < bit_error_rate {
+                    errors |= 1 << bit;
+                }
+            }
+
+            let crc = crc16::State::<crc16::KERMIT>::calculate(&data.to_le_bytes());
+            let word = ((data as u32) | ((crc as u32) << 16)) ^ errors;
+            let computed = crc16::State::<crc16::KERMIT>::calculate(&word.to_le_bytes()[0..2]);
+            if (word >> 16) as u16 != computed {
+                crc_retransmits += 1;
+            }
+
+            match decode_up_to(encode(data) ^ errors, 1) {
+                Some((decoded, _)) => assert_eq!(decoded, data),
+                None => one_bit_retransmits += 1,
+            }
+            match decode_up_to(encode(data) ^ errors, 2) {
+                Some((decoded, _)) => assert_eq!(decoded, data),
+                None => two_bits_retransmits += 1,
+            }
+        }
+        // About 15% of the words have flipped bits, about 1% have more than
+        // one and less than 0.1% more than two
+        assert!(crc_retransmits > nb_words / 10);
+        assert!(one_bit_retransmits < nb_words / 50);
+        assert!(one_bit_retransmits * 10 < crc_retransmits);
+        assert!(two_bits_retransmits < nb_words / 500);
+        assert!(two_bits_retransmits * 10 < one_bit_retransmits);
+    }
+}
@@ -0,0 +1,92 @@
+//! Geometry of the key matrix
+//!
+//! Each half scans its keys as rows and columns, the columns of the right half
+//! coming after those of the left one in the layout.  Key events carry the row
+//! and the column in 4 bits each, so the wire format could address matrices
+//! up to 16x16.
+
+/// Rows and columns of the key matrix
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Geometry {
+    /// Number of rows, the thumb row included
+    pub rows: usize,
+    /// Number of columns of each half
+    pub half_cols: usize,
+}
+
+/// Number of rows key events can address
+pub const WIRE_ROWS: usize = 16;
+/// Number of columns key events can address
+pub const WIRE_COLS: usize = 16;
+
+/// Charybdis 3x5+3 and Charybdis Nano: 3 rows of 5 keys and 3 thumb keys on
+/// each half
+pub const CHARYBDIS_3X5: Geometry = Geometry {
+    rows: 4,
+    half_cols: 5,
+};
+
+/// Geometry of the keyboard the firmware is built for, matching its pins and
+/// keymaps
+pub const GEOMETRY: Geometry = CHARYBDIS_3X5;
+
+/// Number of rows of the keyboard
+pub const ROWS: usize = GEOMETRY.rows;
+/// Number of columns of each half
+pub const HALF_COLS: usize = GEOMETRY.half_cols;
+/// Number of columns of the whole keyboard
+pub const COLS: usize = GEOMETRY.cols();
+
+const _: () = assert!(GEOMETRY.is_supported());
+
+impl Geometry {
+    /// Number of columns of the whole keyboard
+    pub const fn cols(&self) -> usize {
+        2 * self.half_cols
+    }
+
+    /// Whether the key at row @r, column @c is in the matrix
+    pub const fn contains(&self, r: u8, c: u8) -> bool {
+        (r as usize) < self.rows && (c as usize) < self.cols()
+    }
+
+    /// Whether the keys can be sent to the other side
+    /// Each key needs to be addressable by key events and to have a bit in
+    /// the key snapshots, where the keys of a half take the lower bits and
+    /// one more bit tells the half.
+    pub const fn is_supported(&self) -> bool {
+        self.rows <= WIRE_ROWS
+            && self.cols() <= WIRE_COLS
+            && self.rows * self.cols() <= u64::BITS as usize
+            && self.rows * self.half_cols < u32::BITS as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_contains() {
+        assert!(CHARYBDIS_3X5.contains(3, 9));
+        assert!(!CHARYBDIS_3X5.contains(4, 0));
+        assert!(!CHARYBDIS_3X5.contains(0, 10));
+    }
+
+    #[test]
+    fn test_supported() {
+        assert!(CHARYBDIS_3X5.is_supported());
+        let wire = Geometry {
+            rows: WIRE_ROWS,
+            half_cols: WIRE_COLS / 2,
+        };
+        // Addressable, but too many keys for a snapshot
+        assert!(!wire.is_supported());
+        let too_wide = Geometry {
+            rows: 2,
+            half_cols: WIRE_COLS / 2 + 1,
+        };
+        assert!(!too_wide.is_supported());
+    }
+}
@@ -0,0 +1,310 @@
+//! Journal of the words exchanged with the other side
+//!
+//! The last words sent and received are kept in a ring buffer, along with the
+//! time and how they were decoded, so that a lost key can be traced back on
+//! the wire.  A dump of the journal, one word per line, can be turned into a
+//! mermaid sequence diagram on the host.
+
+use crate::serde::{deserialize_frame, frame_len, Event, Message, MAX_FRAME_LEN};
+use crate::sid::Sid;
+use arraydeque::{ArrayDeque, Saturating};
+use core::fmt;
+
+/// Number of words kept in the journal
+pub const JOURNAL_SIZE: usize = 128;
+
+/// Way a word went on the wire
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Direction {
+    /// Sent to the other side
+    Sent,
+    /// Received from the other side
+    Received,
+}
+
+impl Direction {
+    /// Tag of the direction in a dump
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Direction::Sent => "tx",
+            Direction::Received => "rx",
+        }
+    }
+
+    /// From the tag of the direction in a dump
+    fn from_tag(tag: &str) -> Option<Self> {
+        match tag {
+            "tx" => Some(Direction::Sent),
+            "rx" => Some(Direction::Received),
+            _ => None,
+        }
+    }
+}
+
+/// How a word was decoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Decoded {
+    /// First word of a frame, carrying this event with this sequence id
+    Event(Event, Sid),
+    /// Continuation word of an extended frame
+    Continuation,
+    /// First word of a frame that could not be decoded
+    Invalid,
+}
+
+/// Word recorded in the journal
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Entry {
+    /// Time at which the word was sent or received, in ms
+    pub at: u64,
+    /// Whether the word was sent or received
+    pub direction: Direction,
+    /// The word itself
+    pub msg: Message,
+    /// How the word was decoded
+    pub decoded: Decoded,
+}
+
+impl fmt::Display for Entry {
+    /// Line of a dump: `journal <ms> <tx|rx> <word in hexadecimal>`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "journal {} {} {:08x}",
+            self.at,
+            self.direction.as_str(),
+            self.msg
+        )
+    }
+}
+
+/// Last words sent and received, the oldest ones being dropped first
+#[derive(Debug, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Journal {
+    /// Words, oldest first
+    #[cfg_attr(feature = "defmt", defmt(Debug2Format))]
+    entries: ArrayDeque<Entry, JOURNAL_SIZE, Saturating>,
+}
+
+impl Journal {
+    /// Create an empty journal
+    pub fn new() -> Self {
+        Self {
+            entries: ArrayDeque::new(),
+        }
+    }
+
+    /// Record the words of a frame, with the event they were decoded into
+    pub fn record(
+        &mut self,
+        at: u64,
+        direction: Direction,
+        words: &[Message],
+        decoded: Option<(Event, Sid)>,
+    ) {
+        for (i, msg) in words.iter().enumerate() {
+            let decoded = match (i, decoded) {
+                (0, Some((event, sid))) => Decoded::Event(event, sid),
+                (0, None) => Decoded::Invalid,
+                _ => Decoded::Continuation,
+            };
+            if self.entries.is_full() {
+                self.entries.pop_front();
+            }
+            let _ = self.entries.push_back(Entry {
+                at,
+                direction,
+                msg: *msg,
+                decoded,
+            });
+        }
+    }
+
+    /// Words recorded, oldest first
+    pub fn iter(&self) -> impl Iterator<Item = &Entry> {
+        self.entries.iter()
+    }
+
+    /// Number of words recorded
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether no word was recorded
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Forget all the words recorded
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+/// Parse a line of a dump
+/// What comes before `journal`, like the prefix of the logger, and after the
+/// word is ignored.
+fn parse_line(line: &str) -> Option<(u64, Direction, Message)> {
+    let (_, fields) = line.split_once("journal ")?;
+    let mut fields = fields.split_whitespace();
+    let at = fields.next()?.parse().ok()?;
+    let direction = Direction::from_tag(fields.next()?)?;
+    let msg = fields.next()?;
+    let msg = Message::from_str_radix(msg.trim_start_matches("0x"), 16).ok()?;
+    Some((at, direction, msg))
+}
+
+/// Event as shown on the diagram
+struct Label(Event);
+
+impl fmt::Display for Label {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Event::Ack(sid) => write!(f, "Ack({})", sid),
+            Event::AckUpTo(sid) => write!(f, "AckUpTo({})", sid),
+            Event::Retransmit(sid) => write!(f, "Retransmit({})", sid),
+            event => write!(f, "{:?}", event),
+        }
+    }
+}
+
+/// Write a journal dump as a mermaid sequence diagram
+/// The dump comes from the right side if @is_right, from the left one
+/// otherwise.  The frames are decoded again from their words.
+pub fn write_mermaid<W: fmt::Write>(out: &mut W, dump: &str, is_right: bool) -> fmt::Result {
+    let (local, remote) = if is_right { ("R", "L") } else { ("L", "R") };
+    writeln!(out, "sequenceDiagram")?;
+    writeln!(out, "    participant L as Left")?;
+    writeln!(out, "    participant R as Right")?;
+    let mut words = dump.lines().filter_map(parse_line).peekable();
+    while let Some((at, direction, header)) = words.next() {
+        let mut frame = [header; MAX_FRAME_LEN];
+        let mut len = 1;
+        while len < frame_len(header) {
+            match words.next_if(|(_, d, _)| *d == direction) {
+                Some((_, _, msg)) => {
+                    frame[len] = msg;
+                    len += 1;
+                }
+                None => break,
+            }
+        }
+        let (from, to) = match direction {
+            Direction::Sent => (local, remote),
+            Direction::Received => (remote, local),
+        };
+        match deserialize_frame(&frame[..len]) {
+            Ok((event, sid)) if event.is_sequenced() => {
+                let label = Label(event);
+                writeln!(
+                    out,
+                    "    {} ->> {} : {}ms [{}] {}",
+                    from, to, at, sid, label
+                )?
+            }
+            Ok((event, _)) => writeln!(out, "    {} ->> {} : {}ms {}", from, to, at, Label(event))?,
+            Err(_) => writeln!(out, "    {} -x {} : {}ms 0x{:08x}", from, to, at, header)?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mouse_move::MouseMove;
+    use crate::serde::{serialize, serialize_frame};
+
+    #[test]
+    fn test_ring() {
+        let mut journal = Journal::new();
+        assert!(journal.is_empty());
+        for i in 0..JOURNAL_SIZE as u32 + 3 {
+            journal.record(i as u64, Direction::Sent, &[i], None);
+        }
+        assert_eq!(journal.len(), JOURNAL_SIZE);
+        assert_eq!(journal.iter().next().map(|e| e.msg), Some(3));
+        journal.clear();
+        assert!(journal.is_empty());
+    }
+
+    #[test]
+    fn test_record_frame() {
+        let mut journal = Journal::new();
+        let event = Event::SeedRng32(42);
+        let frame = serialize_frame(event, Sid::new(7)).unwrap();
+        journal.record(
+            12,
+            Direction::Received,
+            frame.words(),
+            Some((event, Sid::new(7))),
+        );
+        let decoded: Vec<_> = journal.iter().map(|e| e.decoded).collect();
+        assert_eq!(
+            decoded,
+            [
+                Decoded::Event(event, Sid::new(7)),
+                Decoded::Continuation,
+                Decoded::Continuation
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_line() {
+        let entry = Entry {
+            at: 1234,
+            direction: Direction::Received,
+            msg: 0x00ab_0012,
+            decoded: Decoded::Invalid,
+        };
+        let line = format!("{}", entry);
+        assert_eq!(line, "journal 1234 rx 00ab0012");
+        assert_eq!(
+            parse_line(&line),
+            Some((1234, Direction::Received, 0x00ab_0012))
+        );
+        assert_eq!(
+            parse_line("0.123 INFO  journal 5 tx 0xff (Event(Ping, 3))"),
+            Some((5, Direction::Sent, 0xff))
+        );
+        assert_eq!(parse_line("INFO  Link Up"), None);
+        assert_eq!(parse_line("journal 5 up 0xff"), None);
+    }
+
+    #[test]
+    fn test_mermaid() {
+        let press = serialize(Event::Press(0, 1), Sid::new(3)).unwrap();
+        let ack = serialize(Event::Ack(Sid::new(3)), Sid::new(9)).unwrap();
+        let mouse_move = Event::MouseMove(MouseMove { dx: 2, dy: -1 });
+        let frame = serialize_frame(mouse_move, Sid::new(4)).unwrap();
+        let mut dump = format!("journal 10 tx {:08x}\n", press);
+        dump += &format!("journal 11 rx {:08x}\n", ack ^ 0x0001_0101);
+        dump += "some other log line\n";
+        dump += &format!("journal 12 rx {:08x}\n", ack);
+        for msg in frame.words() {
+            dump += &format!("journal 13 tx {:08x}\n", msg);
+        }
+        let mut out = String::new();
+        write_mermaid(&mut out, &dump, true).unwrap();
+        assert_eq!(
+            out,
+            format!(
+                "sequenceDiagram
+    participant L as Left
+    participant R as Right
+    R ->> L : 10ms [3] Press(0, 1)
+    L -x R : 11ms 0x{:08x}
+    L ->> R : 12ms Ack(3)
+    R ->> L : 13ms [4] {:?}
+",
+                ack ^ 0x0001_0101,
+                mouse_move
+            )
+        );
+    }
+}
@@ -0,0 +1,203 @@
+use crate::geometry::{COLS, GEOMETRY, HALF_COLS, ROWS};
+use crate::serde::Event;
+
+/// Bit set in the snapshot of the right half
+const RIGHT_HALF: u32 = 1 << (ROWS * HALF_COLS);
+
+/// Keys held on the keyboard, one bit per key
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct KeyMatrix {
+    /// Bit `r * COLS + c` is set when the key at row `r`, column `c` is held
+    bits: u64,
+}
+
+impl KeyMatrix {
+    /// Create a matrix without any key held
+    pub const fn new() -> Self {
+        Self { bits: 0 }
+    }
+
+    /// Bit of the key, if in the matrix
+    fn bit(r: u8, c: u8) -> Option<u64> {
+        if GEOMETRY.contains(r, c) {
+            Some(1 << (r as usize * COLS + c as usize))
+        } else {
+            None
+        }
+    }
+
+    /// Set whether the key is held
+    /// Keys out of the matrix are ignored
+    pub fn set(&mut self, r: u8, c: u8, pressed: bool) {
+        if let Some(bit) = Self::bit(r, c) {
+            if pressed {
+                self.bits |= bit;
+            } else {
+                self.bits &= !bit;
+            }
+        }
+    }
+
+    /// Whether the key is held
+    pub fn is_pressed(&self, r: u8, c: u8) -> bool {
+        Self::bit(r, c).is_some_and(|bit| self.bits & bit != 0)
+    }
+
+    /// Whether no key is held
+    pub fn is_empty(&self) -> bool {
+        self.bits == 0
+    }
+
+    /// Keys held, as (row, column)
+    pub fn pressed(&self) -> impl Iterator<Item = (u8, u8)> + '_ {
+        (0..ROWS * COLS)
+            .filter(|i| self.bits & (1 << i) != 0)
+            .map(|i| ((i / COLS) as u8, (i % COLS) as u8))
+    }
+
+    /// Events turning the keys held into those of @target
+    /// Releases come first, then presses.
+    pub fn diff(&self, target: &KeyMatrix) -> impl Iterator<Item = Event> {
+        let released = KeyMatrix {
+            bits: self.bits & !target.bits,
+        };
+        let pressed = KeyMatrix {
+            bits: target.bits & !self.bits,
+        };
+        (0..ROWS * COLS)
+            .filter(move |i| released.bits & (1 << i) != 0)
+            .map(|i| Event::Release((i / COLS) as u8, (i % COLS) as u8))
+            .chain(
+                (0..ROWS * COLS)
+                    .filter(move |i| pressed.bits & (1 << i) != 0)
+                    .map(|i| Event::Press((i / COLS) as u8, (i % COLS) as u8)),
+            )
+    }
+
+    /// To u32, with the keys of a single half
+    /// The lower 20 bits are the keys of the half, then a bit is set for the
+    /// right half.  Gives `None` if keys are held on both halves.
+    pub fn to_u32(&self) -> Option<u32> {
+        let mut left = 0;
+        let mut right = 0;
+        for (r, c) in self.pressed() {
+            let bit = 1 << (r as usize * HALF_COLS + c as usize % HALF_COLS);
+            if (c as usize) < HALF_COLS {
+                left |= bit;
+            } else {
+                right |= bit;
+            }
+        }
+        match (left, right) {
+            (_, 0) => Some(left),
+            (0, _) => Some(right | RIGHT_HALF),
+            _ => None,
+        }
+    }
+
+    /// From u32, see `to_u32`
+    pub const fn from_u32(v: u32) -> Self {
+        let offset = if v & RIGHT_HALF != 0 { HALF_COLS } else { 0 };
+        let mut bits = 0;
+        let mut i = 0;
+        while i < ROWS * HALF_COLS {
+            if v & (1 << i) != 0 {
+                bits |= 1 << (i / HALF_COLS * COLS + offset + i % HALF_COLS);
+            }
+            i += 1;
+        }
+        Self { bits }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matrix(keys: &[(u8, u8)]) -> KeyMatrix {
+        let mut m = KeyMatrix::new();
+        for (r, c) in keys {
+            m.set(*r, *c, true);
+        }
+        m
+    }
+
+    #[test]
+    fn test_set() {
+        let mut m = KeyMatrix::new();
+        assert!(m.is_empty());
+        m.set(3, 9, true);
+        m.set(0, 0, true);
+        m.set(ROWS as u8, 0, true);
+        m.set(0, COLS as u8, true);
+        assert!(m.is_pressed(3, 9));
+        assert!(m.is_pressed(0, 0));
+        assert!(!m.is_pressed(0, 9));
+        assert_eq!(m.pressed().collect::<Vec<_>>(), [(0, 0), (3, 9)]);
+        m.set(3, 9, false);
+        m.set(0, 0, false);
+        assert!(m.is_empty());
+    }
+
+    #[test]
+    fn test_ser_de() {
+        // First column of the right half
+        let h = HALF_COLS as u8;
+        let last_row = ROWS as u8 - 1;
+        let last_col = COLS as u8 - 1;
+        for keys in [
+            &[][..],
+            &[(0, 0)],
+            &[(0, 0), (1, 2), (last_row, h - 1)],
+            &[(0, h)],
+            &[
+                (2, h + 2),
+                (last_row, h),
+                (last_row, last_col),
+                (0, last_col),
+            ],
+        ] {
+            let m = matrix(keys);
+            assert_eq!(KeyMatrix::from_u32(m.to_u32().unwrap()), m);
+        }
+        assert_eq!(matrix(&[(0, h - 1), (0, h)]).to_u32(), None);
+    }
+
+    #[test]
+    fn test_diff_same() {
+        let m = matrix(&[(1, 1), (2, 3)]);
+        assert_eq!(m.diff(&m).count(), 0);
+    }
+
+    #[test]
+    fn test_diff_stuck_key() {
+        // A release was lost
+        let believed = matrix(&[(1, 6), (3, 5)]);
+        let snapshot = matrix(&[(3, 5)]);
+        assert_eq!(
+            believed.diff(&snapshot).collect::<Vec<_>>(),
+            [Event::Release(1, 6)]
+        );
+    }
+
+    #[test]
+    fn test_diff_missing_press() {
+        let believed = matrix(&[]);
+        let snapshot = matrix(&[(0, 2), (2, 4)]);
+        assert_eq!(
+            believed.diff(&snapshot).collect::<Vec<_>>(),
+            [Event::Press(0, 2), Event::Press(2, 4)]
+        );
+    }
+
+    #[test]
+    fn test_diff_releases_first() {
+        let believed = matrix(&[(2, 2), (3, 3)]);
+        let snapshot = matrix(&[(0, 1), (3, 3)]);
+        assert_eq!(
+            believed.diff(&snapshot).collect::<Vec<_>>(),
+            [Event::Release(2, 2), Event::Press(0, 1)]
+        );
+    }
+}
@@ -3,6 +3,10 @@
 /// Serialization and deserialization of key events
 pub mod serde;
 
+/// Forward error correction of the messages
+#[cfg(feature = "fec")]
+pub mod fec;
+
 /// Compule LED Data to render RGB Animations
 pub mod rgb_anims;
 
@@ -15,8 +19,33 @@ pub mod log;
 /// Mouse moves
 pub mod mouse_move;
 
+/// Acceleration of the pointer
+pub mod acceleration;
+
+/// Scrolling with the ball
+pub mod scroll;
+
+/// Resolution of the sensor
+pub mod cpi;
+
+/// Automatic mouse layer
+pub mod auto_mouse;
+
+/// Geometry of the key matrix
+pub mod geometry;
+
+/// Keys held on the keyboard
+pub mod key_matrix;
+
 /// Sequence Id
 pub mod sid;
 
+/// Journal of the words exchanged with the other side
+pub mod journal;
+
 /// Protocol
 pub mod protocol;
+
+/// Simulation of the protocol over a lossy channel
+#[cfg(target_arch = "x86_64")]
+pub mod sim;
@@ -1,5 +1,5 @@
 /// Mouse move event
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct MouseMove {
     /// Delta X
@@ -5,6 +5,9 @@
 //!
 //! See: https://en.wikipedia.org/wiki/Xorshift
 
+/// Seed used instead of 0, which would only ever generate 0
+const DEFAULT_SEED: u32 = 0x2545_f491;
+
 /// Xorshift32 PRNG
 pub struct XorShift32 {
     state: u32,
@@ -13,7 +16,8 @@ pub struct XorShift32 {
 impl XorShift32 {
     /// Create a new XorShift32 PRNG
     pub fn new(seed: u32) -> Self {
-        Self { state: seed }
+        let state = if seed == 0 { DEFAULT_SEED } else { seed };
+        Self { state }
     }
 
     /// Get the next random number
@@ -26,3 +30,23 @@ impl XorShift32 {
         x
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_same_seed() {
+        let mut a = XorShift32::new(42);
+        let mut b = XorShift32::new(42);
+        for _ in 0..100 {
+            assert_eq!(a.random(), b.random());
+        }
+    }
+
+    #[test]
+    fn test_zero_seed() {
+        let mut prng = XorShift32::new(0);
+        assert_ne!(prng.random(), 0);
+    }
+}
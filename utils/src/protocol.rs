@@ -1,11 +1,195 @@
 //! Protocol between the halves.
 
+#[cfg(feature = "journal")]
+use crate::journal::{Direction, Journal};
 #[cfg(feature = "log-protocol")]
 use crate::log::*;
-use crate::serde::{deserialize, serialize, Event, Message};
-use crate::sid::{CircBuf, Sid};
+use crate::serde::{
+    deserialize_frame, frame_len, serialize_frame, Event, Message, Priority, HELLO_FIELD_MAX,
+    MAX_FRAME_LEN, NB_PRIORITIES,
+};
+use crate::sid::{CircBuf, Sid, SID_MAX};
+use arraydeque::{ArrayDeque, Saturating};
 use core::future;
 
+/// Maximum number of events of each priority class waiting to be sent
+pub const QUEUE_SIZE: usize = 16;
+
+/// Number of sequence ids that can be used by events waiting for an ack.
+/// With at most half of the sequence ids in flight, the receiver can tell a
+/// message sent again from a message revealing a gap.
+pub const WINDOW_SIZE: usize = 16;
+
+/// Default time to wait for an ack before sending a message again, in ms
+pub const RETRANSMIT_TIMEOUT_MS: u64 = 50;
+
+/// Messages received this many sequence ids ago or less may be duplicates
+const DUPLICATE_WINDOW: usize = WINDOW_SIZE;
+/// Times a message is sent again without an ack while the other side is
+/// heard, or words received too far ahead in a row, after which the sides
+/// start over from the first sequence id
+const MAX_OUT_OF_SYNC: u8 = 8;
+
+/// Silence after which the link is considered degraded, in ms
+/// The right side sends a ping every 3s, so at least one message should have
+/// been received in that time frame.
+pub const LINK_DEGRADED_MS: u64 = 4000;
+/// Silence after which the link is considered down, in ms
+pub const LINK_DOWN_MS: u64 = 7000;
+/// Round trip time of a ping above which the link is considered degraded, in ms
+pub const LINK_SLOW_RTT_MS: u64 = 20;
+
+/// Version of the protocol, to be increased on any change to the messages
+/// Only the lower 4 bits are exchanged
+/// - 2: extended frames
+/// - 3: cumulative acks
+/// - 4: link speed changes
+/// - 5: reset messages
+/// - 6: key snapshots
+/// - 7: half-duplex turns and unsequenced acks
+/// - 8: sensor commands
+pub const PROTOCOL_VERSION: u8 = 8;
+
+/// Capability: acks may cover every message received up to a sequence id
+pub const CAP_CUMULATIVE_ACK: u8 = 0b0001;
+/// Number of messages received before their cumulative ack is sent, it is
+/// otherwise sent on the next tick
+const CUMULATIVE_ACK_BATCH: u8 = 4;
+/// Capability: the link speed can be changed at runtime
+pub const CAP_LINK_SPEED: u8 = 0b0100;
+
+/// Speeds of the link, in bauds
+/// The link starts at the first one, and falls back to it when it goes down.
+pub const LINK_SPEEDS: [u32; 4] = [57_600, 115_200, 230_400, 460_800];
+/// Time without errors before trying the next speed, in ms
+pub const LINK_SPEED_UP_MS: u64 = 10_000;
+/// Period over which the errors are counted, in ms
+const LINK_SPEED_PERIOD_MS: u64 = 1000;
+/// Errors within a period that make the link fall back to the previous speed
+const LINK_SPEED_DOWN_ERRORS: u32 = 4;
+/// Time for the other side to be heard at a new speed before going back to
+/// the previous one, in ms
+pub const LINK_SPEED_PROBE_MS: u64 = 250;
+
+/// Maximum number of words waiting for the turn of this side to talk, in
+/// half duplex
+const OUTBOX_SIZE: usize = 64;
+/// Silence of the follower after which the leader takes the turn back, in
+/// half duplex, in ms
+pub const TURN_TIMEOUT_MS: u64 = 10;
+
+/// Hello field holding the protocol version
+const HELLO_VERSION: u8 = 1;
+/// First of the 2 hello fields holding the capabilities, lower nibble first
+const HELLO_CAPS: u8 = 2;
+/// First of the 8 hello fields holding the build hash, lower nibble first
+const HELLO_HASH: u8 = 4;
+
+/// Outcome of the handshake with the other side
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Handshake {
+    /// Waiting for the other side to introduce itself
+    Pending,
+    /// Both sides run the same protocol version and firmware build
+    Compatible,
+    /// The other side runs a different protocol version or firmware build
+    Incompatible,
+}
+
+/// Role of a side sharing a single wire with the other one
+/// Both sides take turns to talk: the leader polls the follower by giving it
+/// the turn, and takes it back once the follower is done.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum HalfDuplex {
+    /// Polls the other side
+    Leader,
+    /// Talks when polled
+    Follower,
+}
+
+/// State of the link with the other side
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum LinkState {
+    /// Nothing received from the other side yet
+    Connecting,
+    /// Messages are flowing and pings are answered in time
+    Up,
+    /// Messages are lost or pings are answered late
+    Degraded,
+    /// Nothing received from the other side for too long
+    Down,
+}
+
+/// Occupancy of the sending window
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct WindowStats {
+    /// Number of sequence ids between the oldest unacknowledged one and the
+    /// next one to send
+    pub in_flight: usize,
+    /// Highest number of sequence ids in flight seen so far
+    pub max_in_flight: usize,
+    /// Number of events waiting in the queue
+    pub queued: usize,
+    /// Number of sequence ids missing from the other side, waiting to be
+    /// sent again
+    pub gaps: usize,
+    /// Number of times an event had to wait because the window was full
+    pub stalls: u32,
+    /// Number of events dropped because they would have reused an
+    /// unacknowledged sequence id and their queue had no room to hold them
+    /// back
+    pub drops: u32,
+    /// Number of cosmetic events dropped while queued, superseded by a newer
+    /// one or for lack of room
+    pub coalesced: u32,
+    /// Number of messages dropped because too many words were waiting for
+    /// the turn of this side, in half duplex
+    pub outbox_drops: u32,
+}
+
+/// Counters on the messages exchanged with the other side
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ProtocolStats {
+    /// Messages sent, retransmissions included
+    pub sent: u32,
+    /// Valid messages received
+    pub received: u32,
+    /// Messages received that could not be deserialized, mostly because of a
+    /// CRC mismatch
+    pub crc_errors: u32,
+    /// Gaps detected in the sequence ids received
+    pub sid_gaps: u32,
+    /// Retransmit requests answered with the message asked for
+    pub retransmits_served: u32,
+    /// Retransmit requests for a message no longer available
+    pub retransmits_missed: u32,
+    /// Messages sent again because their ack did not come in time
+    pub timeouts: u32,
+    /// Round trip time of the last ping acknowledged, in ms
+    pub rtt: u64,
+    /// Highest ping round trip time seen so far, in ms
+    pub max_rtt: u64,
+    /// Current speed of the link, in bauds
+    pub link_speed: u32,
+    /// Times the other side announced it restarted
+    pub peer_resets: u32,
+    /// Times this side started over, out of sync with the other one
+    pub restarts: u32,
+}
+
+/// Protocol errors
+#[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The outbound queue is full, the event has not been queued
+    QueueFull,
+}
+
 /// Hardware trait
 pub trait Hardware {
     /// Send a message
@@ -18,6 +202,22 @@ pub trait Hardware {
 
     /// Set error state
     fn set_error_state(&mut self, error: bool) -> impl future::Future<Output = ()> + Send;
+
+    /// Current time, in milliseconds
+    fn now(&self) -> u64;
+
+    /// The state of the link with the other side changed
+    fn on_link_state(&mut self, state: LinkState) -> impl future::Future<Output = ()> + Send;
+
+    /// The handshake with the other side completed
+    fn on_handshake(&mut self, handshake: Handshake) -> impl future::Future<Output = ()> + Send;
+
+    /// Switch the link to the given speed, in bauds
+    /// Messages already sent must go out at the previous speed.
+    fn set_link_speed(&mut self, speed: u32) -> impl future::Future<Output = ()> + Send;
+
+    /// The other side restarted: the state it lost must be sent again
+    fn on_peer_reset(&mut self) -> impl future::Future<Output = ()> + Send;
 }
 
 #[derive(Debug)]
@@ -27,12 +227,18 @@ pub struct SideProtocol<W: Sized + Hardware> {
     name: &'static str,
 
     /// Events sent to the other side
-    sent: CircBuf<Message>,
-    /// Retransmit requests
-    /// The key is the sid used to retransmit
-    retransmit: CircBuf<Sid>,
+    sent: CircBuf<Event>,
+    /// Sequence ids sent and still waiting for an ack, with the time they
+    /// were last sent
+    unacked: CircBuf<u64>,
     /// Last message received, if from a retransmit request
     last_msg: Option<Message>,
+    /// Events received, to detect duplicates
+    received: CircBuf<Event>,
+    /// Words of the extended frame being received
+    rx_frame: [Message; MAX_FRAME_LEN],
+    /// Number of words of the extended frame received so far
+    rx_frame_len: usize,
 
     /// Expecting sid
     next_rx_sid: Sid,
@@ -42,6 +248,81 @@ pub struct SideProtocol<W: Sized + Hardware> {
     /// Errors in the received sequence ids
     rx_errors: CircBuf<()>,
 
+    /// Events waiting to be sent, one queue per priority class
+    #[cfg_attr(feature = "defmt", defmt(Debug2Format))]
+    queues: [ArrayDeque<Event, QUEUE_SIZE, Saturating>; NB_PRIORITIES],
+    /// Whether the other side is asking for retransmits
+    replaying: bool,
+    /// Statistics on the sending window
+    window: WindowStats,
+
+    /// Time to wait for an ack before sending a message again, in ms
+    retransmit_timeout: u64,
+    /// Time of the last retransmit request sent, in ms
+    last_retransmit_request: u64,
+    /// Time of the last retransmit request received, in ms
+    last_replay: u64,
+
+    /// State of the link with the other side
+    link_state: LinkState,
+    /// Time of the last valid message received, in ms
+    last_rx: Option<u64>,
+    /// Ping waiting for an ack, with the time it was sent
+    ping: Option<(Sid, u64)>,
+    /// Times the oldest message waiting for its ack was sent again while the
+    /// other side was heard
+    ignored_sends: u8,
+    /// Words received in a row with a sequence id too far ahead
+    far_words: u8,
+
+    /// Counters on the messages exchanged
+    stats: ProtocolStats,
+
+    /// Hash of the firmware build, if the handshake is enabled
+    build_hash: Option<u32>,
+    /// Capabilities of this side
+    capabilities: u8,
+    /// Number of messages received in order, waiting for a cumulative ack
+    pending_acks: u8,
+
+    /// Whether this side leads the negotiation of the link speed
+    speed_leader: bool,
+    /// Index of the current speed in `LINK_SPEEDS`
+    speed: usize,
+    /// Highest speed index to try, lowered when a speed does not work
+    max_speed: usize,
+    /// Speed index requested to the other side, waiting for its ack
+    speed_request: Option<usize>,
+    /// Previous speed index with the time of the change, until the other
+    /// side is heard at the new speed
+    speed_probe: Option<(usize, u64)>,
+    /// Start of the period over which errors are counted, with the number of
+    /// errors seen at that time
+    error_period: (u64, u32),
+    /// Time since when no error was seen, in ms
+    clean_since: u64,
+    /// Outcome of the handshake with the other side
+    handshake: Handshake,
+    /// Hello fields received from the other side, one nibble each
+    remote_hello: [u8; HELLO_FIELD_MAX as usize],
+    /// Bitmap of the hello fields received from the other side
+    remote_hello_mask: u16,
+    /// Role of this side when sharing a single wire, `None` in full duplex
+    half_duplex: Option<HalfDuplex>,
+    /// Whether this side may talk, always true in full duplex
+    turn: bool,
+    /// Time since when the other side has not been heard while it had the
+    /// turn, in ms
+    silent_since: u64,
+    /// Words waiting for the turn of this side
+    #[cfg_attr(feature = "defmt", defmt(Debug2Format))]
+    outbox: ArrayDeque<Message, OUTBOX_SIZE, Saturating>,
+    /// Speed to switch to once the words of the current turn are sent
+    deferred_speed: Option<usize>,
+    /// Last words sent and received
+    #[cfg(feature = "journal")]
+    journal: Journal,
+
     /// Hardware
     hw: W,
 }
@@ -52,18 +333,189 @@ impl<W: Sized + Hardware> SideProtocol<W> {
         Self {
             name,
             sent: CircBuf::new(),
-            retransmit: CircBuf::new(),
+            unacked: CircBuf::new(),
             next_rx_sid: Sid::default(),
             next_tx_sid: Sid::default(),
             last_msg: None,
+            received: CircBuf::new(),
+            rx_frame: [0; MAX_FRAME_LEN],
+            rx_frame_len: 0,
             rx_errors: CircBuf::new(),
+            queues: [ArrayDeque::new(), ArrayDeque::new(), ArrayDeque::new()],
+            replaying: false,
+            window: WindowStats::default(),
+            retransmit_timeout: RETRANSMIT_TIMEOUT_MS,
+            last_retransmit_request: 0,
+            last_replay: 0,
+            link_state: LinkState::Connecting,
+            last_rx: None,
+            ping: None,
+            ignored_sends: 0,
+            far_words: 0,
+            stats: ProtocolStats {
+                link_speed: LINK_SPEEDS[0],
+                ..Default::default()
+            },
+            build_hash: None,
+            capabilities: 0,
+            pending_acks: 0,
+            speed_leader: false,
+            speed: 0,
+            max_speed: LINK_SPEEDS.len() - 1,
+            speed_request: None,
+            speed_probe: None,
+            error_period: (0, 0),
+            clean_since: 0,
+            handshake: Handshake::Pending,
+            remote_hello: [0; HELLO_FIELD_MAX as usize],
+            remote_hello_mask: 0,
+            half_duplex: None,
+            turn: true,
+            silent_since: 0,
+            outbox: ArrayDeque::new(),
+            deferred_speed: None,
+            #[cfg(feature = "journal")]
+            journal: Journal::new(),
             hw,
         }
     }
 
+    /// Name of the side
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Hardware
+    pub fn hw(&self) -> &W {
+        &self.hw
+    }
+
+    /// Hardware, mutable
+    pub fn hw_mut(&mut self) -> &mut W {
+        &mut self.hw
+    }
+
+    /// Set the time to wait for an ack before sending a message again, in ms
+    pub fn set_retransmit_timeout(&mut self, timeout: u64) {
+        self.retransmit_timeout = timeout;
+    }
+
+    /// Enable the handshake on link-up, with the hash of the firmware build
+    /// to check against the other side
+    pub fn enable_handshake(&mut self, build_hash: u32) {
+        self.build_hash = Some(build_hash);
+    }
+
+    /// Set the capabilities of this side, advertised to the other side
+    pub fn set_capabilities(&mut self, capabilities: u8) {
+        self.capabilities = capabilities;
+    }
+
+    /// Announce to the other side that this side just started, so that both
+    /// start over from the first sequence id
+    pub async fn announce_reset(&mut self) -> Result<(), Error> {
+        #[cfg(feature = "log-protocol")]
+        info!("[{}] Announcing reset", self.name);
+        self.queue_event(Event::Reset).await
+    }
+
+    /// Lead the negotiation of the link speed, on one side only
+    /// Faster speeds are tried while the link has no errors, slower ones when
+    /// they rise.  Requires `CAP_LINK_SPEED` on both sides.
+    pub fn lead_link_speed(&mut self) {
+        self.speed_leader = true;
+    }
+
+    /// Share a single wire with the other side, with the given role
+    /// Words are held until this side gets the turn to talk.
+    pub fn set_half_duplex(&mut self, role: HalfDuplex) {
+        self.half_duplex = Some(role);
+        self.turn = role == HalfDuplex::Leader;
+    }
+
+    /// Send an event on the wire, with the given sequence id
+    async fn transmit(&mut self, event: Event, sid: Sid) {
+        let frame = serialize_frame(event, sid).unwrap();
+        if !self.turn && OUTBOX_SIZE - self.outbox.len() < frame.words().len() {
+            // Recovered as a lost message
+            self.window.outbox_drops += 1;
+            return;
+        }
+        #[cfg(feature = "journal")]
+        self.journal.record(
+            self.hw.now(),
+            Direction::Sent,
+            frame.words(),
+            Some((event, sid)),
+        );
+        for msg in frame.words() {
+            if self.turn {
+                self.hw.send(*msg).await;
+            } else {
+                let _ = self.outbox.push_back(*msg);
+            }
+        }
+        self.stats.sent += 1;
+    }
+
+    /// In half duplex, this side got the turn to talk
+    async fn take_turn(&mut self) {
+        self.turn = true;
+        if self.half_duplex == Some(HalfDuplex::Leader) {
+            // The follower is done: the words held go at the new speed
+            self.apply_deferred_speed().await;
+        }
+        while let Some(msg) = self.outbox.pop_front() {
+            self.hw.send(msg).await;
+        }
+        if self.half_duplex == Some(HalfDuplex::Follower) {
+            self.give_turn().await;
+        }
+    }
+
+    /// In half duplex, let the other side talk
+    async fn give_turn(&mut self) {
+        self.transmit(Event::Over, self.next_tx_sid).await;
+        self.turn = false;
+        self.silent_since = self.hw.now();
+        if self.half_duplex == Some(HalfDuplex::Follower) {
+            // The acks of this turn went out at the previous speed
+            self.apply_deferred_speed().await;
+        }
+    }
+
     /// Send an event
     async fn send_event(&mut self, event: Event) {
-        let msg = serialize(event, self.next_tx_sid).unwrap();
+        if !event.is_sequenced() {
+            // Acks and retransmit requests do not take a sequence id, so
+            // that they go through even when the window is full
+            #[cfg(feature = "log-protocol")]
+            info!("[{}] Sending Event: {}", self.name, Debug2Format(&event));
+            self.transmit(event, self.next_tx_sid).await;
+            if let Event::Retransmit(_) = event {
+                self.last_retransmit_request = self.hw.now();
+            }
+            return;
+        }
+        if self.unacked.get(self.next_tx_sid).is_some() {
+            // Reusing this sid would lose a message that may still have to
+            // be retransmitted: the event waits for the window to open, ahead
+            // of the events of its class
+            #[cfg(feature = "log-protocol")]
+            warn!(
+                "[{}] Window full, holding Event: {}",
+                self.name,
+                Debug2Format(&event)
+            );
+            self.window.stalls += 1;
+            if self.queues[event.priority() as usize]
+                .push_front(event)
+                .is_err()
+            {
+                self.window.drops += 1;
+            }
+            return;
+        }
         #[cfg(feature = "log-protocol")]
         info!(
             "[{}] Sending [{}] Event: {}",
@@ -71,18 +523,139 @@ impl<W: Sized + Hardware> SideProtocol<W> {
             self.next_tx_sid,
             Debug2Format(&event)
         );
-        self.hw.send(msg).await;
-        self.sent.insert(self.next_tx_sid, msg);
-        if let Event::Retransmit(sid) = event {
-            self.retransmit.insert(self.next_tx_sid, sid);
+        self.transmit(event, self.next_tx_sid).await;
+        self.sent.insert(self.next_tx_sid, event);
+        if event.needs_ack() {
+            self.unacked.insert(self.next_tx_sid, self.hw.now());
+        }
+        if event == Event::Ping {
+            self.ping = Some((self.next_tx_sid, self.hw.now()));
         }
         self.next_tx_sid.next();
+        self.window.max_in_flight = self.window.max_in_flight.max(self.in_flight());
+    }
+
+    /// Oldest sequence id sent and not acknowledged yet
+    pub fn oldest_unacked(&self) -> Option<Sid> {
+        self.next_tx_sid
+            .iter(self.next_tx_sid)
+            .find(|sid| self.unacked.get(*sid).is_some())
+    }
+
+    /// Number of sequence ids between the oldest unacknowledged one and the
+    /// next one to send
+    fn in_flight(&self) -> usize {
+        match self.oldest_unacked() {
+            Some(sid) if sid == self.next_tx_sid => SID_MAX.as_usize() + 1,
+            Some(sid) => sid.distance(self.next_tx_sid),
+            None => 0,
+        }
+    }
+
+    /// Whether a new event waiting for an ack can be sent
+    fn is_window_open(&self) -> bool {
+        self.in_flight() < WINDOW_SIZE
+    }
+
+    /// Statistics on the sending window
+    pub fn window_stats(&self) -> WindowStats {
+        WindowStats {
+            in_flight: self.in_flight(),
+            queued: self.queued(),
+            gaps: self.rx_errors.len(),
+            ..self.window
+        }
+    }
+
+    /// Counters on the messages exchanged with the other side
+    pub fn stats(&self) -> ProtocolStats {
+        self.stats
+    }
+
+    /// Last words sent and received, to trace back what happened on the wire
+    #[cfg(feature = "journal")]
+    pub fn journal(&self) -> &Journal {
+        &self.journal
     }
 
     /// Queue an event to be sent
-    pub async fn queue_event(&mut self, event: Event) {
-        // TODO: really queue events when retransmits are ongoing
-        self.send_event(event).await;
+    /// The event is sent right away unless the other side is asking for
+    /// retransmits or the sending window is full, in which case it is held
+    /// until the replay is over or acks come back.
+    /// Held events are sent by priority class: input first, then state, then
+    /// cosmetics.  A cosmetic event replaces a queued one it supersedes, and
+    /// the oldest cosmetic event is dropped when their queue is full.
+    pub async fn queue_event(&mut self, event: Event) -> Result<(), Error> {
+        let window_open = self.is_window_open();
+        if !self.replaying && self.queued() == 0 && window_open {
+            self.send_event(event).await;
+            return Ok(());
+        }
+        if !window_open {
+            self.window.stalls += 1;
+        }
+        #[cfg(feature = "log-protocol")]
+        info!(
+            "[{}] Queueing Event: {} ({} queued)",
+            self.name,
+            Debug2Format(&event),
+            self.queued()
+        );
+        let priority = event.priority();
+        let queue = &mut self.queues[priority as usize];
+        if priority == Priority::Cosmetic {
+            if let Some(queued) = queue.iter_mut().find(|queued| event.supersedes(queued)) {
+                *queued = event;
+                self.window.coalesced += 1;
+                return Ok(());
+            }
+            if queue.is_full() {
+                queue.pop_front();
+                self.window.coalesced += 1;
+            }
+        }
+        queue.push_back(event).map_err(|_| Error::QueueFull)
+    }
+
+    /// Number of events waiting to be sent
+    fn queued(&self) -> usize {
+        self.queues.iter().map(|queue| queue.len()).sum()
+    }
+
+    /// Whether everything sent has been acknowledged and nothing is missing
+    /// from the other side
+    pub fn is_idle(&self) -> bool {
+        self.oldest_unacked().is_none()
+            && self.queued() == 0
+            && self.rx_errors.is_empty()
+            && self.outbox.is_empty()
+    }
+
+    /// Whether the outbound queue is full
+    /// No more events should be queued until some are sent.  Cosmetic events
+    /// never fill it, they are dropped instead.
+    pub fn is_queue_full(&self) -> bool {
+        self.queues[..Priority::Cosmetic as usize]
+            .iter()
+            .any(|queue| queue.is_full())
+    }
+
+    /// Send the queued events, highest priority first, unless a replay is
+    /// ongoing or the window is full
+    async fn flush_queue(&mut self) {
+        while !self.replaying && self.is_window_open() {
+            match self.queues.iter_mut().find_map(|queue| queue.pop_front()) {
+                Some(event) => self.send_event(event).await,
+                None => break,
+            }
+        }
+    }
+
+    /// Whether the sequence id is the last one sent
+    fn is_last_sent(&self, sid: Sid) -> bool {
+        let mut next = sid;
+        next.next();
+        next == self.next_tx_sid
     }
 
     /// On invalid sequence id
@@ -92,6 +665,20 @@ impl<W: Sized + Hardware> SideProtocol<W> {
             "[{}] Invalid sid received: expected {}, got {}",
             self.name, self.next_rx_sid, sid
         );
+        if self.next_rx_sid.distance(sid) >= WINDOW_SIZE {
+            // The other side is never that far ahead: the message got
+            // corrupted despite its check bits
+            #[cfg(feature = "log-protocol")]
+            warn!("[{}] Sid too far ahead, dropping the message", self.name);
+            self.stats.crc_errors += 1;
+            // Unless they keep coming: this side lost track of the sequence
+            // ids of the other one
+            self.far_words += 1;
+            if self.far_words >= MAX_OUT_OF_SYNC {
+                self.restart().await;
+            }
+            return;
+        }
         if let Some(last_msg) = self.last_msg {
             if last_msg == msg {
                 #[cfg(feature = "log-protocol")]
@@ -99,13 +686,19 @@ impl<W: Sized + Hardware> SideProtocol<W> {
                 return;
             }
         }
+        self.stats.sid_gaps += 1;
+        // A retransmit request is sent once per gap, it is repeated on
+        // timeout if unanswered
+        let known_gap = self.rx_errors.get(self.next_rx_sid).is_some();
         let mut next = sid;
         next.next();
         for s in self.next_rx_sid.iter(next) {
             self.rx_errors.insert(s, ());
         }
-        self.hw.wait_a_bit().await;
-        self.send_event(Event::Retransmit(self.next_rx_sid)).await;
+        if !known_gap {
+            self.hw.wait_a_bit().await;
+            self.send_event(Event::Retransmit(self.next_rx_sid)).await;
+        }
     }
 
     //. Send an ACK for the given sequence id
@@ -115,74 +708,251 @@ impl<W: Sized + Hardware> SideProtocol<W> {
         self.send_event(Event::Ack(sid)).await;
     }
 
+    /// Acknowledge an event received in order
+    /// With cumulative acks, such events are acknowledged together
+    async fn acknowledge_received(&mut self, sid: Sid) {
+        if self.capabilities() & CAP_CUMULATIVE_ACK == 0 {
+            self.acknowledge(sid).await;
+            return;
+        }
+        self.pending_acks += 1;
+        if self.pending_acks >= CUMULATIVE_ACK_BATCH {
+            self.flush_acks().await;
+        }
+    }
+
+    /// Send the cumulative ack of the messages received in order
+    async fn flush_acks(&mut self) {
+        if self.pending_acks == 0 {
+            return;
+        }
+        self.pending_acks = 0;
+        let mut last = self.next_rx_sid;
+        last.prev();
+        #[cfg(feature = "log-protocol")]
+        info!("[{}] Sending ACK for sids up to {}", self.name, last);
+        self.send_event(Event::AckUpTo(last)).await;
+    }
+
     /// Received an ACK for the given sequence id
     /// This means the other side has received this event
     async fn on_ack(&mut self, sid: Sid) {
+        let speed_change = match self.sent.get(sid) {
+            Some(Event::SetLinkSpeed(speed)) => Some(speed as usize),
+            _ => None,
+        };
+        if self.unacked.get(sid).is_some() {
+            self.ignored_sends = 0;
+        }
         self.sent.remove(sid);
+        self.unacked.remove(sid);
+        if let Some((ping_sid, sent_at)) = self.ping {
+            if ping_sid == sid {
+                self.stats.rtt = self.hw.now().saturating_sub(sent_at);
+                self.stats.max_rtt = self.stats.max_rtt.max(self.stats.rtt);
+                self.ping = None;
+            }
+        }
+        if self.is_last_sent(sid) {
+            // The other side has caught up
+            self.replaying = false;
+        }
+        if let Some(speed) = speed_change {
+            // The other side switched once its ack sent: follow it and make
+            // sure it can hear this side
+            self.speed_request = None;
+            self.switch_speed_after_turn(speed).await;
+            let _ = self.queue_event(Event::Ping).await;
+        }
+    }
+
+    /// Received a cumulative ACK: the other side has received every event
+    /// up to the given sequence id
+    async fn on_ack_up_to(&mut self, sid: Sid) {
+        let Some(oldest) = self.oldest_unacked() else {
+            return;
+        };
+        if oldest.distance(sid) >= WINDOW_SIZE {
+            // Acknowledges nothing in flight, it is late
+            return;
+        }
+        let mut end = sid;
+        end.next();
+        for s in oldest.iter(end) {
+            if self.unacked.get(s).is_some() {
+                self.on_ack(s).await;
+            }
+        }
     }
 
     /// On Ping event: respond with a ack
+    /// The ack is not delayed, it gives the round trip time
     async fn on_ping(&mut self, sid: Sid) {
         #[cfg(feature = "log-protocol")]
         info!("[{}] Acknowledge Ping of Sid {}", self.name, sid);
-        self.acknowledge(sid).await;
+        self.acknowledge_received(sid).await;
+        self.flush_acks().await;
     }
 
     /// On Retransmit event
     /// The other side is asking for a retransmit
     /// Send the event again with the same sequence id
     async fn on_retansmit(&mut self, sid: Sid) {
-        if let Some(msg) = self.sent.get(sid) {
+        if let Some(event) = self.sent.get(sid) {
             #[cfg(feature = "log-protocol")]
             info!(
                 "[{}] Retransmitting [{}] Event: {}",
                 self.name,
                 sid,
-                Debug2Format(&deserialize(msg).unwrap().0)
+                Debug2Format(&event)
             );
-            self.hw.send(msg).await;
+            self.transmit(event, sid).await;
+            self.stats.retransmits_served += 1;
+            if self.unacked.get(sid).is_some() {
+                self.unacked.insert(sid, self.hw.now());
+            }
+            // Hold new events until the other side has caught up
+            self.replaying = !self.is_last_sent(sid);
+            self.last_replay = self.hw.now();
         } else {
             #[cfg(feature = "log-protocol")]
             warn!("[{}] No event to retransmit for sid {}", self.name, sid);
+            self.stats.retransmits_missed += 1;
+            let distance = sid.distance(self.next_tx_sid);
+            let ahead = self.next_tx_sid.distance(sid);
+            if ahead < WINDOW_SIZE {
+                // Nothing sent with this sequence id yet, the request was
+                // triggered by a corrupted message: pings take the sequence
+                // ids up to it so that the other side is not left waiting
+                for _ in 0..=ahead {
+                    if !self.is_window_open() {
+                        break;
+                    }
+                    self.send_event(Event::Ping).await;
+                }
+            } else if distance <= WINDOW_SIZE {
+                // Already acknowledged: fill the gap so that the other side
+                // can move on
+                self.transmit(Event::Noop, sid).await;
+            }
+            self.replaying = false;
+        }
+    }
+
+    /// On Reset event: the other side just started
+    /// Everything in flight is forgotten, both sides start over from the
+    /// first sequence id.
+    async fn on_reset(&mut self, sid: Sid) {
+        #[cfg(feature = "log-protocol")]
+        warn!("[{}] The other side restarted", self.name);
+        self.stats.peer_resets += 1;
+        self.clear_session();
+        self.next_rx_sid = sid;
+        self.next_rx_sid.next();
+        self.received.insert(sid, Event::Reset);
+        self.acknowledge(sid).await;
+        self.reset_link_speed().await;
+        self.hw.set_error_state(false).await;
+        self.hw.on_peer_reset().await;
+        self.start_handshake().await;
+    }
+
+    /// Forget the messages exchanged so far, to start over from the first
+    /// sequence id
+    fn clear_session(&mut self) {
+        self.sent = CircBuf::new();
+        self.unacked = CircBuf::new();
+        self.received = CircBuf::new();
+        self.rx_errors = CircBuf::new();
+        self.last_msg = None;
+        self.replaying = false;
+        self.pending_acks = 0;
+        self.ping = None;
+        self.ignored_sends = 0;
+        self.far_words = 0;
+        // Words held for the next turn carry the previous sequence ids
+        self.outbox.clear();
+        self.next_tx_sid = Sid::default();
+        self.next_rx_sid = Sid::default();
+    }
+
+    /// The sides disagree on the sequence ids, after a corrupted word passed
+    /// the check bits: start over as after a restart, the events in flight
+    /// are lost
+    async fn restart(&mut self) {
+        #[cfg(feature = "log-protocol")]
+        warn!(
+            "[{}] Out of sync with the other side, starting over",
+            self.name
+        );
+        self.stats.restarts += 1;
+        self.clear_session();
+        self.send_event(Event::Reset).await;
+    }
+
+    /// Whether the event has already been received and processed
+    fn is_duplicate(&self, event: Event, sid: Sid) -> bool {
+        let distance = sid.distance(self.next_rx_sid);
+        // A gap filler may come after the message it replaces
+        distance > 0
+            && distance <= DUPLICATE_WINDOW
+            && (event == Event::Noop || self.received.get(sid) == Some(event))
+    }
+
+    /// On duplicate message: the ack may have been lost, send it again
+    async fn on_duplicate(&mut self, event: Event, sid: Sid) {
+        #[cfg(feature = "log-protocol")]
+        info!(
+            "[{}] Duplicate [{}] Event: {}",
+            self.name,
+            sid,
+            Debug2Format(&event)
+        );
+        if event.needs_ack() {
+            self.acknowledge(sid).await;
         }
     }
 
     /// On Ok event
     async fn handle_received_event(&mut self, msg: Message, event: Event, sid: Sid) {
+        self.received.insert(sid, event);
         match event {
             Event::Noop => {}
             Event::Ping => {
                 self.on_ping(sid).await;
             }
-            Event::Retransmit(err) => {
-                self.on_retansmit(err).await;
+            // Out of the sequence, handled as soon as received
+            Event::Retransmit(_)
+            | Event::Ack(_)
+            | Event::AckUpTo(_)
+            | Event::Reset
+            | Event::Over => {}
+            Event::Hello(field, nibble) => {
+                self.acknowledge_received(sid).await;
+                self.on_hello(field, nibble).await;
             }
-            Event::Ack(ack) => {
-                self.on_ack(ack).await;
+            Event::SetLinkSpeed(speed) => {
+                self.acknowledge_received(sid).await;
+                self.on_set_link_speed(speed).await;
             }
             _ => {
-                self.acknowledge(sid).await;
-                self.hw.process_event(event).await;
+                self.acknowledge_received(sid).await;
+                if self.handshake == Handshake::Incompatible {
+                    #[cfg(feature = "log-protocol")]
+                    warn!(
+                        "[{}] Ignoring event from an incompatible side: {}",
+                        self.name,
+                        Debug2Format(&event)
+                    );
+                } else {
+                    self.hw.process_event(event).await;
+                }
             }
         }
         // If this Sequence Id had an error, we can clear it now
         if self.rx_errors.get(sid).is_some() {
             self.last_msg = Some(msg);
             self.rx_errors.remove(sid);
-            /* Remove related retransmit requests */
-            for idx in Sid::new(0).iter(Sid::new(0)) {
-                if let Some(s) = self.retransmit.get(idx) {
-                    if s == sid {
-                        #[cfg(feature = "log-protocol")]
-                        info!(
-                            "[{}] Removing retransmit request for sid {} at {}",
-                            self.name, sid, idx
-                        );
-                        self.sent.remove(idx);
-                        self.retransmit.remove(idx);
-                    }
-                }
-            }
         } else {
             self.last_msg = None;
         }
@@ -198,8 +968,40 @@ impl<W: Sized + Hardware> SideProtocol<W> {
     }
 
     /// Receive a message
+    /// Words of extended frames are gathered until the frame is complete
     pub async fn receive(&mut self, msg: Message) {
-        match deserialize(msg) {
+        if !self.turn {
+            self.silent_since = self.hw.now();
+        }
+        if self.rx_frame_len > 0 {
+            self.rx_frame[self.rx_frame_len] = msg;
+            self.rx_frame_len += 1;
+            if self.rx_frame_len < frame_len(self.rx_frame[0]) {
+                return;
+            }
+            let words = self.rx_frame;
+            let len = core::mem::take(&mut self.rx_frame_len);
+            self.receive_frame(&words[..len]).await;
+        } else if frame_len(msg) > 1 {
+            self.rx_frame[0] = msg;
+            self.rx_frame_len = 1;
+        } else {
+            self.receive_frame(&[msg]).await;
+        }
+    }
+
+    /// Receive a complete frame
+    async fn receive_frame(&mut self, words: &[Message]) {
+        let msg = words[0];
+        let decoded = deserialize_frame(words);
+        #[cfg(feature = "journal")]
+        self.journal.record(
+            self.hw.now(),
+            Direction::Received,
+            words,
+            decoded.as_ref().ok().copied(),
+        );
+        match decoded {
             Ok((event, sid)) => {
                 #[cfg(feature = "log-protocol")]
                 info!(
@@ -209,9 +1011,29 @@ impl<W: Sized + Hardware> SideProtocol<W> {
                     self.next_rx_sid,
                     Debug2Format(&event)
                 );
-                if self.next_rx_sid != sid {
+                self.last_rx = Some(self.hw.now());
+                self.stats.received += 1;
+                // The other side is heard at the current speed
+                self.speed_probe = None;
+                if let Event::Ack(ack) = event {
+                    self.on_ack(ack).await;
+                } else if let Event::AckUpTo(ack) = event {
+                    self.on_ack_up_to(ack).await;
+                } else if let Event::Retransmit(err) = event {
+                    self.on_retansmit(err).await;
+                } else if event == Event::Over {
+                    if self.half_duplex.is_some() {
+                        self.take_turn().await;
+                    }
+                } else if event == Event::Reset {
+                    // Whatever the sequence ids, the other side starts over
+                    self.on_reset(sid).await;
+                } else if self.is_duplicate(event, sid) {
+                    self.on_duplicate(event, sid).await;
+                } else if self.next_rx_sid != sid {
                     self.on_invalid_sid(msg, sid).await;
                 } else {
+                    self.far_words = 0;
                     self.next_rx_sid.next();
                     self.handle_received_event(msg, event, sid).await;
                 }
@@ -219,87 +1041,497 @@ impl<W: Sized + Hardware> SideProtocol<W> {
             Err(_) => {
                 #[cfg(feature = "log-protocol")]
                 warn!("[{}] Unable to deserialize event: 0x{:04x}", self.name, msg);
+                self.stats.crc_errors += 1;
                 self.hw.wait_a_bit().await;
                 self.send_event(Event::Retransmit(self.next_rx_sid)).await;
             }
         }
+        self.update_link_state().await;
+        self.flush_queue().await;
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use arraydeque::ArrayDeque;
-    use lovely_env_logger;
+    /// Handle timeouts, to be called periodically
+    ///
+    /// Pending cumulative acks are sent, messages not acknowledged in time
+    /// are sent again, and retransmit requests that went unanswered are
+    /// repeated.
+    pub async fn tick(&mut self) {
+        self.flush_acks().await;
+        self.update_link_speed().await;
 
-    struct MockHardware {
-        msg_sent: usize,
-        queue: ArrayDeque<Message, 64, arraydeque::behavior::Saturating>,
-        on_error: bool,
-    }
-    impl Hardware for MockHardware {
-        fn send(&mut self, msg: Message) -> impl future::Future<Output = ()> + Send {
-            self.msg_sent += 1;
-            self.queue.push_front(msg).unwrap();
-            async {}
-        }
-        fn wait_a_bit(&mut self) -> impl future::Future<Output = ()> + Send {
-            async {}
-        }
-        fn process_event(&mut self, _event: Event) -> impl future::Future<Output = ()> + Send {
-            async {}
+        // Read after the sends above, which stamp the time they went out
+        let now = self.hw.now();
+        let timeout = self.retransmit_timeout;
+
+        if !self.rx_errors.is_empty() && now.saturating_sub(self.last_retransmit_request) >= timeout
+        {
+            #[cfg(feature = "log-protocol")]
+            warn!(
+                "[{}] Retransmit request timed out, asking again for sid {}",
+                self.name, self.next_rx_sid
+            );
+            self.send_event(Event::Retransmit(self.next_rx_sid)).await;
         }
-        fn set_error_state(&mut self, error: bool) -> impl future::Future<Output = ()> + Send {
-            self.on_error = error;
-            async {}
+
+        if self.replaying && now.saturating_sub(self.last_replay) >= timeout {
+            // The other side stopped asking for retransmits
+            self.replaying = false;
         }
-    }
-    impl MockHardware {
-        fn new() -> Self {
-            Self {
-                msg_sent: 0,
-                queue: ArrayDeque::new(),
-                on_error: false,
+
+        if let Some(oldest) = self.oldest_unacked() {
+            let mut ignored = false;
+            for sid in oldest.iter(self.next_tx_sid) {
+                match (self.unacked.get(sid), self.sent.get(sid)) {
+                    (Some(sent_at), Some(event)) if now.saturating_sub(sent_at) >= timeout => {
+                        #[cfg(feature = "log-protocol")]
+                        warn!(
+                            "[{}] Ack timed out, sending again [{}] Event: {}",
+                            self.name,
+                            sid,
+                            Debug2Format(&event)
+                        );
+                        ignored |= sid == oldest && self.last_rx.is_some_and(|t| t >= sent_at);
+                        self.transmit(event, sid).await;
+                        self.unacked.insert(sid, now);
+                        self.stats.timeouts += 1;
+                    }
+                    _ => {}
+                }
+            }
+            if ignored {
+                self.ignored_sends += 1;
+                if self.ignored_sends >= MAX_OUT_OF_SYNC {
+                    self.restart().await;
+                }
             }
         }
-    }
 
-    /// Communicate between the two sides
-    async fn communicate(
-        right: &mut SideProtocol<MockHardware>,
-        left: &mut SideProtocol<MockHardware>,
-    ) {
-        loop {
-            if let Some(msg) = left.hw.queue.pop_back() {
-                right.receive(msg).await;
-            }
-            if let Some(msg) = right.hw.queue.pop_back() {
-                left.receive(msg).await;
+        self.update_link_state().await;
+        self.flush_queue().await;
+
+        if self.half_duplex == Some(HalfDuplex::Leader) {
+            if !self.turn && now.saturating_sub(self.silent_since) >= TURN_TIMEOUT_MS {
+                // The turn given or the end of the reply was lost
+                self.take_turn().await;
             }
-            if right.hw.queue.is_empty() && left.hw.queue.is_empty() {
-                break;
+            if self.turn {
+                self.give_turn().await;
             }
         }
     }
 
-    /// Is side stable
-    fn is_stable(side: &SideProtocol<MockHardware>) -> bool {
-        for i in Sid::new(0).iter(Sid::new(0)) {
-            if let Some(msg) = side.sent.get(i) {
-                let (event, _) = deserialize(msg).unwrap();
-                if !event.is_ack() {
-                    error!("[{}/{}] Not acked: {}", side.name, i, Debug2Format(&event));
-                    return false;
-                }
-            }
-        }
-        true
+    /// Current speed of the link, in bauds
+    pub fn link_speed(&self) -> u32 {
+        LINK_SPEEDS[self.speed]
     }
 
-    #[tokio::test]
-    async fn test_protocol_synced() {
-        let _ = lovely_env_logger::try_init_default();
-        let hw_right = MockHardware::new();
+    /// Number of errors seen on the link, in both directions
+    fn nb_errors(&self) -> u32 {
+        self.stats.crc_errors
+            + self.stats.sid_gaps
+            + self.stats.retransmits_served
+            + self.stats.timeouts
+    }
+
+    /// Switch to the speed at the given index in `LINK_SPEEDS`
+    /// Until the other side is heard at the new speed, the previous one is
+    /// kept to go back to it.
+    async fn switch_speed(&mut self, speed: usize) {
+        #[cfg(feature = "log-protocol")]
+        info!(
+            "[{}] Link speed: {} -> {}",
+            self.name, LINK_SPEEDS[self.speed], LINK_SPEEDS[speed]
+        );
+        let now = self.hw.now();
+        self.speed_probe = Some((self.speed, now));
+        self.speed = speed;
+        // Errors are expected while both sides switch
+        self.error_period = (now, self.nb_errors());
+        self.clean_since = now;
+        self.stats.link_speed = LINK_SPEEDS[speed];
+        self.hw.set_link_speed(LINK_SPEEDS[speed]).await;
+    }
+
+    /// Switch to the speed at the given index once the words of the current
+    /// turn are sent, in half duplex, right away otherwise
+    async fn switch_speed_after_turn(&mut self, speed: usize) {
+        if self.turn {
+            self.switch_speed(speed).await;
+        } else {
+            self.deferred_speed = Some(speed);
+        }
+    }
+
+    /// Apply the switch of speed deferred until the end of the turn
+    async fn apply_deferred_speed(&mut self) {
+        if let Some(speed) = self.deferred_speed.take() {
+            self.switch_speed(speed).await;
+        }
+    }
+
+    /// Go back to the first speed, for instance when the link went down
+    async fn reset_link_speed(&mut self) {
+        self.max_speed = LINK_SPEEDS.len() - 1;
+        self.speed_request = None;
+        self.deferred_speed = None;
+        if self.speed != 0 {
+            self.switch_speed(0).await;
+        }
+        self.speed_probe = None;
+    }
+
+    /// On SetLinkSpeed event: the leading side asks to switch speed
+    async fn on_set_link_speed(&mut self, speed: u8) {
+        // The ack must go out at the current speed
+        self.flush_acks().await;
+        let speed = speed as usize;
+        if speed < LINK_SPEEDS.len() && speed != self.speed {
+            self.switch_speed_after_turn(speed).await;
+        } else if speed >= LINK_SPEEDS.len() {
+            #[cfg(feature = "log-protocol")]
+            warn!("[{}] Unsupported link speed index {}", self.name, speed);
+        }
+    }
+
+    /// Ask the other side to switch to the speed at the given index
+    /// This side switches once the request is acknowledged.
+    async fn request_speed(&mut self, speed: usize) {
+        #[cfg(feature = "log-protocol")]
+        info!(
+            "[{}] Requesting link speed {}",
+            self.name, LINK_SPEEDS[speed]
+        );
+        self.error_period = (self.hw.now(), self.nb_errors());
+        self.clean_since = self.hw.now();
+        if self
+            .queue_event(Event::SetLinkSpeed(speed as u8))
+            .await
+            .is_ok()
+        {
+            self.speed_request = Some(speed);
+        }
+    }
+
+    /// Check how the current speed performs
+    /// Without news from the other side after a switch, the previous speed
+    /// is restored.  The leading side steps up the speed while there are no
+    /// errors and down when they rise.
+    async fn update_link_speed(&mut self) {
+        let now = self.hw.now();
+        if let Some((previous, at)) = self.speed_probe {
+            if now.saturating_sub(at) >= LINK_SPEED_PROBE_MS {
+                #[cfg(feature = "log-protocol")]
+                warn!(
+                    "[{}] Nothing heard at {} bauds, going back to {}",
+                    self.name, LINK_SPEEDS[self.speed], LINK_SPEEDS[previous]
+                );
+                if previous < self.speed {
+                    self.max_speed = previous;
+                }
+                self.switch_speed(previous).await;
+                self.speed_probe = None;
+            }
+            return;
+        }
+        if !self.speed_leader
+            || self.capabilities() & CAP_LINK_SPEED == 0
+            || self.speed_request.is_some()
+        {
+            return;
+        }
+        let (start, errors_at_start) = self.error_period;
+        let errors = self.nb_errors() - errors_at_start;
+        if errors >= LINK_SPEED_DOWN_ERRORS && self.speed > 0 {
+            self.max_speed = self.speed - 1;
+            self.request_speed(self.speed - 1).await;
+            return;
+        }
+        if now.saturating_sub(start) >= LINK_SPEED_PERIOD_MS {
+            if errors > 0 {
+                self.clean_since = now;
+            }
+            self.error_period = (now, self.nb_errors());
+        }
+        if errors == 0
+            && self.link_state == LinkState::Up
+            && self.speed < self.max_speed
+            && now.saturating_sub(self.clean_since) >= LINK_SPEED_UP_MS
+        {
+            self.request_speed(self.speed + 1).await;
+        }
+    }
+
+    /// Outcome of the handshake with the other side
+    pub fn handshake(&self) -> Handshake {
+        self.handshake
+    }
+
+    /// Capabilities supported by both sides
+    pub fn capabilities(&self) -> u8 {
+        match self.handshake {
+            Handshake::Compatible => self.capabilities & self.remote_capabilities(),
+            _ => 0,
+        }
+    }
+
+    /// Capabilities advertised by the other side
+    fn remote_capabilities(&self) -> u8 {
+        let idx = (HELLO_CAPS - 1) as usize;
+        self.remote_hello[idx] | (self.remote_hello[idx + 1] << 4)
+    }
+
+    /// Build hash advertised by the other side
+    fn remote_build_hash(&self) -> u32 {
+        let idx = (HELLO_HASH - 1) as usize;
+        self.remote_hello[idx..idx + 8]
+            .iter()
+            .rev()
+            .fold(0, |hash, nibble| (hash << 4) | *nibble as u32)
+    }
+
+    /// Introduce this side to the other one
+    async fn start_handshake(&mut self) {
+        let Some(build_hash) = self.build_hash else {
+            return;
+        };
+        #[cfg(feature = "log-protocol")]
+        info!(
+            "[{}] Starting handshake, build hash 0x{:08x}",
+            self.name, build_hash
+        );
+        self.handshake = Handshake::Pending;
+        self.remote_hello_mask = 0;
+        let mut hello = [0; HELLO_FIELD_MAX as usize];
+        hello[(HELLO_VERSION - 1) as usize] = PROTOCOL_VERSION & 0xf;
+        for i in 0..2 {
+            hello[(HELLO_CAPS - 1) as usize + i] = (self.capabilities >> (4 * i)) & 0xf;
+        }
+        for i in 0..8 {
+            hello[(HELLO_HASH - 1) as usize + i] = ((build_hash >> (4 * i)) & 0xf) as u8;
+        }
+        for (field, nibble) in (1..=HELLO_FIELD_MAX).zip(hello) {
+            if self.queue_event(Event::Hello(field, nibble)).await.is_err() {
+                #[cfg(feature = "log-protocol")]
+                error!("[{}] Unable to queue the handshake", self.name);
+                return;
+            }
+        }
+    }
+
+    /// On Hello event: one field of the introduction of the other side
+    async fn on_hello(&mut self, field: u8, nibble: u8) {
+        if self.build_hash.is_none() {
+            return;
+        }
+        if field == HELLO_VERSION {
+            if self.handshake != Handshake::Pending {
+                // The other side restarted the handshake, answer it
+                self.start_handshake().await;
+            }
+            self.remote_hello_mask = 0;
+        }
+        self.remote_hello[(field - 1) as usize] = nibble;
+        self.remote_hello_mask |= 1 << (field - 1);
+        if self.remote_hello_mask != (1 << HELLO_FIELD_MAX) - 1
+            || self.handshake != Handshake::Pending
+        {
+            return;
+        }
+        let version = self.remote_hello[(HELLO_VERSION - 1) as usize];
+        let build_hash = self.remote_build_hash();
+        let compatible = version == PROTOCOL_VERSION & 0xf && Some(build_hash) == self.build_hash;
+        self.handshake = if compatible {
+            Handshake::Compatible
+        } else {
+            Handshake::Incompatible
+        };
+        #[cfg(feature = "log-protocol")]
+        info!(
+            "[{}] Handshake: {}, version {}, build hash 0x{:08x}, capabilities 0x{:02x}",
+            self.name,
+            Debug2Format(&self.handshake),
+            version,
+            build_hash,
+            self.remote_capabilities()
+        );
+        self.hw.on_handshake(self.handshake).await;
+    }
+
+    /// State of the link with the other side
+    pub fn link_state(&self) -> LinkState {
+        self.link_state
+    }
+
+    /// Round trip time of the pending ping, or of the last one acknowledged
+    fn rtt(&self, now: u64) -> u64 {
+        match self.ping {
+            Some((_, sent_at)) => self.stats.rtt.max(now.saturating_sub(sent_at)),
+            None => self.stats.rtt,
+        }
+    }
+
+    /// Compute the state of the link and notify the hardware on changes
+    async fn update_link_state(&mut self) {
+        let now = self.hw.now();
+        let state = match self.last_rx {
+            None => LinkState::Connecting,
+            Some(t) if now.saturating_sub(t) >= LINK_DOWN_MS => LinkState::Down,
+            Some(t)
+                if now.saturating_sub(t) >= LINK_DEGRADED_MS
+                    || self.rtt(now) >= LINK_SLOW_RTT_MS
+                    || !self.rx_errors.is_empty() =>
+            {
+                LinkState::Degraded
+            }
+            Some(_) => LinkState::Up,
+        };
+        if state != self.link_state {
+            #[cfg(feature = "log-protocol")]
+            info!(
+                "[{}] Link state: {} -> {}",
+                self.name,
+                Debug2Format(&self.link_state),
+                Debug2Format(&state)
+            );
+            let was_connected = matches!(self.link_state, LinkState::Up | LinkState::Degraded);
+            self.link_state = state;
+            self.hw.on_link_state(state).await;
+            if state == LinkState::Down {
+                self.reset_link_speed().await;
+            }
+            if !was_connected && matches!(state, LinkState::Up | LinkState::Degraded) {
+                self.start_handshake().await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(not(feature = "log-protocol"))]
+    use crate::log::*;
+    use crate::rgb_anims::RgbAnimType;
+    use crate::serde::{deserialize, serialize};
+    use arraydeque::ArrayDeque;
+    use lovely_env_logger;
+
+    struct MockHardware {
+        msg_sent: usize,
+        queue: ArrayDeque<Message, 64, arraydeque::behavior::Saturating>,
+        on_error: bool,
+        /// Events processed, in order
+        events: Vec<Event>,
+        /// Mock clock, in ms
+        now: u64,
+        /// Time the clock moves forward on every read, in ms
+        clock_step: u64,
+        /// Time the clock moved forward through reads so far, in ms
+        clock_drift: core::cell::Cell<u64>,
+        /// Link states notified, in order
+        link_states: Vec<LinkState>,
+        /// Handshake outcomes notified, in order
+        handshakes: Vec<Handshake>,
+        /// Link speeds set, in order
+        speeds: Vec<u32>,
+        /// Number of resets of the other side notified
+        peer_resets: usize,
+    }
+    impl Hardware for MockHardware {
+        fn send(&mut self, msg: Message) -> impl future::Future<Output = ()> + Send {
+            self.msg_sent += 1;
+            self.queue.push_front(msg).unwrap();
+            async {}
+        }
+        fn wait_a_bit(&mut self) -> impl future::Future<Output = ()> + Send {
+            async {}
+        }
+        fn process_event(&mut self, event: Event) -> impl future::Future<Output = ()> + Send {
+            self.events.push(event);
+            async {}
+        }
+        fn set_error_state(&mut self, error: bool) -> impl future::Future<Output = ()> + Send {
+            self.on_error = error;
+            async {}
+        }
+        fn now(&self) -> u64 {
+            self.clock_drift
+                .set(self.clock_drift.get() + self.clock_step);
+            self.now + self.clock_drift.get()
+        }
+        fn on_link_state(&mut self, state: LinkState) -> impl future::Future<Output = ()> + Send {
+            self.link_states.push(state);
+            async {}
+        }
+        fn on_handshake(
+            &mut self,
+            handshake: Handshake,
+        ) -> impl future::Future<Output = ()> + Send {
+            self.handshakes.push(handshake);
+            async {}
+        }
+        fn set_link_speed(&mut self, speed: u32) -> impl future::Future<Output = ()> + Send {
+            self.speeds.push(speed);
+            async {}
+        }
+        fn on_peer_reset(&mut self) -> impl future::Future<Output = ()> + Send {
+            self.peer_resets += 1;
+            async {}
+        }
+    }
+    impl MockHardware {
+        fn new() -> Self {
+            Self {
+                msg_sent: 0,
+                queue: ArrayDeque::new(),
+                on_error: false,
+                events: Vec::new(),
+                now: 0,
+                clock_step: 0,
+                clock_drift: core::cell::Cell::new(0),
+                link_states: Vec::new(),
+                handshakes: Vec::new(),
+                speeds: Vec::new(),
+                peer_resets: 0,
+            }
+        }
+    }
+
+    /// Communicate between the two sides
+    async fn communicate(
+        right: &mut SideProtocol<MockHardware>,
+        left: &mut SideProtocol<MockHardware>,
+    ) {
+        loop {
+            if let Some(msg) = left.hw.queue.pop_back() {
+                right.receive(msg).await;
+            }
+            if let Some(msg) = right.hw.queue.pop_back() {
+                left.receive(msg).await;
+            }
+            if right.hw.queue.is_empty() && left.hw.queue.is_empty() {
+                break;
+            }
+        }
+    }
+
+    /// Is side stable
+    fn is_stable(side: &SideProtocol<MockHardware>) -> bool {
+        for i in Sid::new(0).iter(Sid::new(0)) {
+            if let Some(event) = side.sent.get(i) {
+                if !event.is_ack() {
+                    error!("[{}/{}] Not acked: {}", side.name, i, Debug2Format(&event));
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    #[tokio::test]
+    async fn test_protocol_synced() {
+        let _ = lovely_env_logger::try_init_default();
+        let hw_right = MockHardware::new();
         let hw_left = MockHardware::new();
         let mut right = SideProtocol::new(hw_right, "right");
         let mut left = SideProtocol::new(hw_left, "left");
@@ -365,4 +1597,976 @@ mod tests {
         assert!(is_stable(&right));
         assert!(is_stable(&left));
     }
+
+    #[tokio::test]
+    async fn test_queue_while_replaying() {
+        let _ = lovely_env_logger::try_init_default();
+        let hw_right = MockHardware::new();
+        let hw_left = MockHardware::new();
+        let mut right = SideProtocol::new(hw_right, "right");
+        let mut left = SideProtocol::new(hw_left, "left");
+
+        let first = [
+            Event::Press(0, 1),
+            Event::Release(0, 1),
+            Event::Press(1, 2),
+            Event::Release(1, 2),
+        ];
+        let second = [Event::Press(2, 3), Event::Release(2, 3)];
+
+        // Send 4 events from right to left, corrupting the first one
+        for event in first.iter() {
+            right.queue_event(*event).await.unwrap();
+        }
+        let msg = right.hw.queue.back_mut().unwrap();
+        *msg ^= 0x1234;
+
+        // The left side asks for a retransmit, which starts a replay
+        let msg = right.hw.queue.pop_back().unwrap();
+        left.receive(msg).await;
+        let msg = left.hw.queue.pop_back().unwrap();
+        right.receive(msg).await;
+        assert!(right.replaying);
+
+        // New events are held until the replay is over
+        for event in second.iter() {
+            right.queue_event(*event).await.unwrap();
+        }
+        assert_eq!(right.queued(), second.len());
+
+        communicate(&mut right, &mut left).await;
+        assert_eq!(right.queued(), 0);
+        assert!(is_stable(&right));
+        assert!(is_stable(&left));
+        let expected: Vec<Event> = first.iter().chain(second.iter()).copied().collect();
+        assert_eq!(left.hw.events, expected);
+    }
+
+    #[tokio::test]
+    async fn test_queue_full() {
+        let _ = lovely_env_logger::try_init_default();
+        let hw_right = MockHardware::new();
+        let hw_left = MockHardware::new();
+        let mut right = SideProtocol::new(hw_right, "right");
+        let mut left = SideProtocol::new(hw_left, "left");
+
+        right.replaying = true;
+        for _ in 0..QUEUE_SIZE {
+            right.queue_event(Event::Press(0, 0)).await.unwrap();
+        }
+        assert!(right.is_queue_full());
+        assert_eq!(
+            right.queue_event(Event::Press(0, 0)).await,
+            Err(Error::QueueFull)
+        );
+        assert_eq!(right.hw.msg_sent, 0);
+
+        // Once the replay is over, the queue is flushed in order
+        right.replaying = false;
+        right.flush_queue().await;
+        assert!(!right.is_queue_full());
+        communicate(&mut right, &mut left).await;
+        assert_eq!(left.hw.events.len(), QUEUE_SIZE);
+        assert!(is_stable(&right));
+    }
+
+    #[tokio::test]
+    async fn test_queue_priorities() {
+        let _ = lovely_env_logger::try_init_default();
+        let mut right = SideProtocol::new(MockHardware::new(), "right");
+        let mut left = SideProtocol::new(MockHardware::new(), "left");
+
+        right.replaying = true;
+        for event in [
+            Event::RgbAnimChangeLayer(1),
+            Event::Press(0, 1),
+            Event::RgbAnim(RgbAnimType::Wheel),
+            Event::SeedRng(7),
+            Event::RgbAnimChangeLayer(2),
+            Event::Release(0, 1),
+            Event::RgbAnim(RgbAnimType::Pulse),
+            Event::RgbAnimChangeLayer(3),
+        ] {
+            right.queue_event(event).await.unwrap();
+        }
+        // Superseded cosmetic events are coalesced
+        assert_eq!(right.queued(), 5);
+        assert_eq!(right.window_stats().coalesced, 3);
+        assert!(!right.is_queue_full());
+
+        right.replaying = false;
+        right.flush_queue().await;
+        communicate(&mut right, &mut left).await;
+        assert_eq!(
+            left.hw.events,
+            vec![
+                Event::Press(0, 1),
+                Event::Release(0, 1),
+                Event::SeedRng(7),
+                Event::RgbAnimChangeLayer(3),
+                Event::RgbAnim(RgbAnimType::Pulse),
+            ]
+        );
+        assert!(is_stable(&right));
+    }
+
+    /// Key event number `i`
+    fn key_event(i: usize) -> Event {
+        let (r, c) = ((i / 10 % 4) as u8, (i % 10) as u8);
+        if i & 1 == 0 {
+            Event::Press(r, c)
+        } else {
+            Event::Release(r, c)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_window_without_acks() {
+        let _ = lovely_env_logger::try_init_default();
+        let hw_right = MockHardware::new();
+        let hw_left = MockHardware::new();
+        let mut right = SideProtocol::new(hw_right, "right");
+        let mut left = SideProtocol::new(hw_left, "left");
+
+        // Push more than 32 events with the ack path cut off
+        let nb_events = WINDOW_SIZE + QUEUE_SIZE;
+        for i in 0..nb_events {
+            right.queue_event(key_event(i)).await.unwrap();
+        }
+        assert_eq!(
+            right.queue_event(key_event(nb_events)).await,
+            Err(Error::QueueFull)
+        );
+        assert_eq!(right.hw.msg_sent, WINDOW_SIZE);
+        assert_eq!(right.oldest_unacked(), Some(Sid::new(0)));
+        let stats = right.window_stats();
+        assert_eq!(stats.in_flight, WINDOW_SIZE);
+        assert_eq!(stats.max_in_flight, WINDOW_SIZE);
+        assert_eq!(stats.queued, QUEUE_SIZE);
+        assert_eq!(stats.stalls, QUEUE_SIZE as u32 + 1);
+
+        let mut acks = Vec::new();
+        while let Some(msg) = right.hw.queue.pop_back() {
+            left.receive(msg).await;
+            while let Some(ack) = left.hw.queue.pop_back() {
+                acks.push(ack);
+            }
+        }
+        assert_eq!(acks.len(), WINDOW_SIZE);
+
+        // None of the unacknowledged messages got overwritten
+        for (i, sid) in Sid::new(0).iter(Sid::new(WINDOW_SIZE as u8)).enumerate() {
+            let event = right.sent.get(sid).unwrap();
+            assert_eq!(event, key_event(i));
+        }
+
+        // Restore the ack path: the queue drains in order
+        for ack in acks {
+            right.receive(ack).await;
+        }
+        communicate(&mut right, &mut left).await;
+        assert_eq!(right.queued(), 0);
+        assert!(is_stable(&right));
+        let expected: Vec<Event> = (0..nb_events).map(key_event).collect();
+        assert_eq!(left.hw.events, expected);
+    }
+
+    #[tokio::test]
+    async fn test_sid_in_use_holds_event() {
+        let _ = lovely_env_logger::try_init_default();
+        let mut right = SideProtocol::new(MockHardware::new(), "right");
+        let mut left = SideProtocol::new(MockHardware::new(), "left");
+
+        // Every sequence id is in use, none of it acknowledged
+        let nb_sids = SID_MAX.as_usize() + 1;
+        for i in 0..nb_sids {
+            right.send_event(key_event(i)).await;
+        }
+        let mut msgs = Vec::new();
+        while let Some(msg) = right.hw.queue.pop_back() {
+            msgs.push(msg);
+        }
+
+        // The next event is held back rather than dropped
+        right.send_event(key_event(nb_sids)).await;
+        assert!(right.hw.queue.is_empty());
+        assert_eq!(right.queued(), 1);
+        assert_eq!(right.window_stats().drops, 0);
+
+        // Once acknowledged, it goes out after the others
+        for msg in msgs {
+            left.receive(msg).await;
+        }
+        communicate(&mut right, &mut left).await;
+        for _ in 0..4 {
+            advance(&mut right, &mut left, RETRANSMIT_TIMEOUT_MS).await;
+            communicate(&mut right, &mut left).await;
+        }
+        assert!(is_stable(&right));
+        let expected: Vec<Event> = (0..=nb_sids).map(key_event).collect();
+        assert_eq!(left.hw.events, expected);
+    }
+
+    #[tokio::test]
+    async fn test_window_full_still_acks() {
+        let _ = lovely_env_logger::try_init_default();
+        let hw_right = MockHardware::new();
+        let hw_left = MockHardware::new();
+        let mut right = SideProtocol::new(hw_right, "right");
+        let mut left = SideProtocol::new(hw_left, "left");
+
+        // Fill the window of the right side, none of it gets acknowledged
+        for i in 0..WINDOW_SIZE {
+            right.queue_event(key_event(i)).await.unwrap();
+        }
+        right.hw.queue.clear();
+
+        // Acks do not take a sequence id: the right side can still
+        // acknowledge every event from the left side
+        let nb_events = SID_MAX.as_usize() + 1;
+        for i in 0..nb_events {
+            left.queue_event(key_event(i)).await.unwrap();
+            let msg = left.hw.queue.pop_back().unwrap();
+            right.receive(msg).await;
+            let ack = right.hw.queue.pop_back().unwrap();
+            left.receive(ack).await;
+        }
+        assert!(left.is_idle());
+        assert_eq!(right.window_stats().in_flight, WINDOW_SIZE);
+        assert_eq!(right.window_stats().drops, 0);
+        for i in 0..WINDOW_SIZE {
+            let event = right.sent.get(Sid::new(i as u8)).unwrap();
+            assert_eq!(event, key_event(i));
+        }
+        let expected: Vec<Event> = (0..nb_events).map(key_event).collect();
+        assert_eq!(right.hw.events, expected);
+    }
+
+    /// Advance the clock of both sides and let them handle timeouts
+    async fn advance(
+        right: &mut SideProtocol<MockHardware>,
+        left: &mut SideProtocol<MockHardware>,
+        ms: u64,
+    ) {
+        right.hw.now += ms;
+        left.hw.now += ms;
+        right.tick().await;
+        left.tick().await;
+    }
+
+    #[tokio::test]
+    async fn test_timeout_lost_last_message() {
+        let _ = lovely_env_logger::try_init_default();
+        let hw_right = MockHardware::new();
+        let hw_left = MockHardware::new();
+        let mut right = SideProtocol::new(hw_right, "right");
+        let mut left = SideProtocol::new(hw_left, "left");
+
+        // The release is lost, nothing comes after it to reveal the gap
+        right.queue_event(Event::Press(1, 1)).await.unwrap();
+        right.queue_event(Event::Release(1, 1)).await.unwrap();
+        right.hw.queue.pop_front().unwrap();
+        communicate(&mut right, &mut left).await;
+        assert_eq!(left.hw.events, vec![Event::Press(1, 1)]);
+        assert!(!is_stable(&right));
+
+        // Not yet timed out
+        advance(&mut right, &mut left, RETRANSMIT_TIMEOUT_MS - 1).await;
+        assert!(right.hw.queue.is_empty());
+
+        advance(&mut right, &mut left, 1).await;
+        assert_eq!(right.hw.queue.len(), 1);
+        communicate(&mut right, &mut left).await;
+        assert_eq!(
+            left.hw.events,
+            vec![Event::Press(1, 1), Event::Release(1, 1)]
+        );
+        assert!(is_stable(&right));
+        assert!(is_stable(&left));
+    }
+
+    #[tokio::test]
+    async fn test_timeout_lost_ack() {
+        let _ = lovely_env_logger::try_init_default();
+        let hw_right = MockHardware::new();
+        let hw_left = MockHardware::new();
+        let mut right = SideProtocol::new(hw_right, "right");
+        let mut left = SideProtocol::new(hw_left, "left");
+        right.set_retransmit_timeout(10);
+
+        right.queue_event(Event::Press(2, 2)).await.unwrap();
+        let msg = right.hw.queue.pop_back().unwrap();
+        left.receive(msg).await;
+        // The ack is lost
+        left.hw.queue.pop_back().unwrap();
+        assert!(!is_stable(&right));
+
+        // The event is sent again, acknowledged, but not processed twice
+        advance(&mut right, &mut left, 10).await;
+        communicate(&mut right, &mut left).await;
+        assert_eq!(left.hw.events, vec![Event::Press(2, 2)]);
+        assert!(is_stable(&right));
+        assert!(left.rx_errors.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_timeout_lost_retransmit_request() {
+        let _ = lovely_env_logger::try_init_default();
+        let hw_right = MockHardware::new();
+        let hw_left = MockHardware::new();
+        let mut right = SideProtocol::new(hw_right, "right");
+        let mut left = SideProtocol::new(hw_left, "left");
+        right.queue_event(Event::Press(0, 3)).await.unwrap();
+        right.queue_event(Event::Release(0, 3)).await.unwrap();
+        // The press is lost, the release reveals the gap
+        right.hw.queue.pop_back().unwrap();
+        let msg = right.hw.queue.pop_back().unwrap();
+        left.receive(msg).await;
+        // The retransmit request is lost
+        left.hw.queue.pop_back().unwrap();
+        assert!(!left.rx_errors.is_empty());
+
+        // Both sides end up filling the gaps of the other one
+        for _ in 0..4 {
+            advance(&mut right, &mut left, RETRANSMIT_TIMEOUT_MS).await;
+            communicate(&mut right, &mut left).await;
+        }
+        assert_eq!(
+            left.hw.events,
+            vec![Event::Press(0, 3), Event::Release(0, 3)]
+        );
+        assert!(left.rx_errors.is_empty());
+        assert!(right.rx_errors.is_empty());
+        assert!(is_stable(&right));
+        assert!(is_stable(&left));
+    }
+
+    #[tokio::test]
+    async fn test_link_state() {
+        let _ = lovely_env_logger::try_init_default();
+        let hw_right = MockHardware::new();
+        let hw_left = MockHardware::new();
+        let mut right = SideProtocol::new(hw_right, "right");
+        let mut left = SideProtocol::new(hw_left, "left");
+        assert_eq!(right.link_state(), LinkState::Connecting);
+        assert_eq!(left.link_state(), LinkState::Connecting);
+
+        right.queue_event(Event::Ping).await.unwrap();
+        communicate(&mut right, &mut left).await;
+        assert_eq!(right.link_state(), LinkState::Up);
+        assert_eq!(left.link_state(), LinkState::Up);
+
+        // Nothing received for a while
+        advance(&mut right, &mut left, LINK_DEGRADED_MS).await;
+        assert_eq!(right.link_state(), LinkState::Degraded);
+        assert_eq!(left.link_state(), LinkState::Degraded);
+        advance(&mut right, &mut left, LINK_DOWN_MS - LINK_DEGRADED_MS).await;
+        assert_eq!(right.link_state(), LinkState::Down);
+        assert_eq!(left.link_state(), LinkState::Down);
+
+        // The link comes back
+        right.queue_event(Event::Ping).await.unwrap();
+        communicate(&mut right, &mut left).await;
+        assert_eq!(right.link_state(), LinkState::Up);
+        assert_eq!(left.link_state(), LinkState::Up);
+        let expected = vec![
+            LinkState::Up,
+            LinkState::Degraded,
+            LinkState::Down,
+            LinkState::Up,
+        ];
+        assert_eq!(right.hw.link_states, expected);
+        assert_eq!(left.hw.link_states, expected);
+    }
+
+    #[tokio::test]
+    async fn test_link_state_slow_ping() {
+        let _ = lovely_env_logger::try_init_default();
+        let hw_right = MockHardware::new();
+        let hw_left = MockHardware::new();
+        let mut right = SideProtocol::new(hw_right, "right");
+        let mut left = SideProtocol::new(hw_left, "left");
+        right.queue_event(Event::Ping).await.unwrap();
+        communicate(&mut right, &mut left).await;
+        assert_eq!(right.link_state(), LinkState::Up);
+
+        // The ack takes too long to come back
+        right.queue_event(Event::Ping).await.unwrap();
+        let msg = right.hw.queue.pop_back().unwrap();
+        left.receive(msg).await;
+        right.hw.now += LINK_SLOW_RTT_MS;
+        communicate(&mut right, &mut left).await;
+        assert_eq!(right.link_state(), LinkState::Degraded);
+
+        // The next one is answered in time
+        right.queue_event(Event::Ping).await.unwrap();
+        communicate(&mut right, &mut left).await;
+        assert_eq!(right.link_state(), LinkState::Up);
+        assert_eq!(
+            right.hw.link_states,
+            vec![LinkState::Up, LinkState::Degraded, LinkState::Up]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stats() {
+        let _ = lovely_env_logger::try_init_default();
+        let hw_right = MockHardware::new();
+        let hw_left = MockHardware::new();
+        let mut right = SideProtocol::new(hw_right, "right");
+        let mut left = SideProtocol::new(hw_left, "left");
+
+        right.queue_event(Event::Press(1, 1)).await.unwrap();
+        right.queue_event(Event::Release(1, 1)).await.unwrap();
+        // The press is lost, the release is corrupted once
+        right.hw.queue.pop_back().unwrap();
+        let msg = right.hw.queue.pop_back().unwrap();
+        left.receive(msg ^ 0x0001_0101).await;
+        left.receive(msg).await;
+        communicate(&mut right, &mut left).await;
+        assert_eq!(
+            left.hw.events,
+            vec![Event::Press(1, 1), Event::Release(1, 1)]
+        );
+
+        right.hw.now += 3;
+        right.queue_event(Event::Ping).await.unwrap();
+        right.hw.now += 2;
+        communicate(&mut right, &mut left).await;
+
+        let stats = left.stats();
+        assert_eq!(stats.crc_errors, 1);
+        assert_eq!(stats.sid_gaps, 1);
+        assert_eq!(stats.sent, left.hw.msg_sent as u32);
+        let stats = right.stats();
+        assert_eq!(stats.crc_errors, 0);
+        assert_eq!(stats.sid_gaps, 0);
+        assert!(stats.retransmits_served >= 1);
+        assert_eq!(stats.sent, right.hw.msg_sent as u32);
+        assert_eq!(stats.received, left.stats().sent);
+        assert_eq!(stats.rtt, 2);
+        assert_eq!(stats.max_rtt, 2);
+    }
+
+    /// Create both sides with the handshake enabled, and bring the link up
+    async fn connect(
+        right_hash: u32,
+        left_hash: u32,
+    ) -> (SideProtocol<MockHardware>, SideProtocol<MockHardware>) {
+        let mut right = SideProtocol::new(MockHardware::new(), "right");
+        let mut left = SideProtocol::new(MockHardware::new(), "left");
+        right.set_capabilities(0b0110);
+        left.set_capabilities(0b0011);
+        right.enable_handshake(right_hash);
+        left.enable_handshake(left_hash);
+        right.queue_event(Event::Ping).await.unwrap();
+        communicate(&mut right, &mut left).await;
+        (right, left)
+    }
+
+    #[tokio::test]
+    async fn test_handshake() {
+        let _ = lovely_env_logger::try_init_default();
+        let (mut right, mut left) = connect(0xdead_beef, 0xdead_beef).await;
+        assert_eq!(right.handshake(), Handshake::Compatible);
+        assert_eq!(left.handshake(), Handshake::Compatible);
+        assert_eq!(right.hw.handshakes, vec![Handshake::Compatible]);
+        assert_eq!(left.hw.handshakes, vec![Handshake::Compatible]);
+        assert_eq!(right.capabilities(), 0b0010);
+        assert_eq!(left.capabilities(), 0b0010);
+        assert!(is_stable(&right));
+        assert!(is_stable(&left));
+
+        right.queue_event(Event::Press(1, 2)).await.unwrap();
+        communicate(&mut right, &mut left).await;
+        assert_eq!(left.hw.events, vec![Event::Press(1, 2)]);
+
+        // The link goes down and comes back: the handshake is done again
+        advance(&mut right, &mut left, LINK_DOWN_MS).await;
+        assert_eq!(right.link_state(), LinkState::Down);
+        assert_eq!(left.link_state(), LinkState::Down);
+        right.queue_event(Event::Ping).await.unwrap();
+        communicate(&mut right, &mut left).await;
+        assert_eq!(right.handshake(), Handshake::Compatible);
+        assert_eq!(left.handshake(), Handshake::Compatible);
+        assert_eq!(right.hw.handshakes.len(), 2);
+        assert_eq!(left.hw.handshakes.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_mismatch() {
+        let _ = lovely_env_logger::try_init_default();
+        let (mut right, mut left) = connect(0x1234_5678, 0x1234_5679).await;
+        assert_eq!(right.handshake(), Handshake::Incompatible);
+        assert_eq!(left.handshake(), Handshake::Incompatible);
+        assert_eq!(left.hw.handshakes, vec![Handshake::Incompatible]);
+        assert_eq!(right.capabilities(), 0);
+
+        // Events from the other side are acknowledged but not processed
+        right.queue_event(Event::Press(1, 2)).await.unwrap();
+        communicate(&mut right, &mut left).await;
+        assert!(left.hw.events.is_empty());
+        assert!(is_stable(&right));
+    }
+
+    #[tokio::test]
+    async fn test_cumulative_ack() {
+        let _ = lovely_env_logger::try_init_default();
+        let mut right = SideProtocol::new(MockHardware::new(), "right");
+        let mut left = SideProtocol::new(MockHardware::new(), "left");
+        right.set_capabilities(CAP_CUMULATIVE_ACK);
+        left.set_capabilities(CAP_CUMULATIVE_ACK);
+        right.enable_handshake(0xdead_beef);
+        left.enable_handshake(0xdead_beef);
+        right.queue_event(Event::Ping).await.unwrap();
+        communicate(&mut right, &mut left).await;
+        assert_eq!(left.capabilities(), CAP_CUMULATIVE_ACK);
+        advance(&mut right, &mut left, 1).await;
+        communicate(&mut right, &mut left).await;
+        assert!(is_stable(&right));
+
+        // 8 events are acknowledged with 2 messages
+        let msg_sent = left.hw.msg_sent;
+        for i in 0..8 {
+            right.queue_event(key_event(i)).await.unwrap();
+        }
+        communicate(&mut right, &mut left).await;
+        assert_eq!(left.hw.msg_sent - msg_sent, 2);
+        assert!(is_stable(&right));
+
+        // The ack of the last events is sent on the next tick
+        for i in 8..10 {
+            right.queue_event(key_event(i)).await.unwrap();
+        }
+        communicate(&mut right, &mut left).await;
+        assert_eq!(left.hw.msg_sent - msg_sent, 2);
+        assert!(!is_stable(&right));
+        advance(&mut right, &mut left, 1).await;
+        communicate(&mut right, &mut left).await;
+        assert_eq!(left.hw.msg_sent - msg_sent, 3);
+        assert!(is_stable(&right));
+
+        // A lost cumulative ack: the events are sent again and acknowledged
+        // one by one
+        for i in 10..14 {
+            right.queue_event(key_event(i)).await.unwrap();
+        }
+        for _ in 0..4 {
+            let msg = right.hw.queue.pop_back().unwrap();
+            left.receive(msg).await;
+        }
+        assert_eq!(left.hw.queue.len(), 1);
+        left.hw.queue.clear();
+        advance(&mut right, &mut left, RETRANSMIT_TIMEOUT_MS).await;
+        communicate(&mut right, &mut left).await;
+        assert!(is_stable(&right));
+        assert_eq!(left.hw.events, (0..14).map(key_event).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_cumulative_ack_not_negotiated() {
+        let _ = lovely_env_logger::try_init_default();
+        let (mut right, mut left) = connect(0xdead_beef, 0xdead_beef).await;
+        assert_eq!(left.capabilities() & CAP_CUMULATIVE_ACK, 0);
+        let msg_sent = left.hw.msg_sent;
+        for i in 0..8 {
+            right.queue_event(key_event(i)).await.unwrap();
+        }
+        communicate(&mut right, &mut left).await;
+        assert_eq!(left.hw.msg_sent - msg_sent, 8);
+        assert!(is_stable(&right));
+    }
+
+    #[tokio::test]
+    async fn test_peer_reset() {
+        let _ = lovely_env_logger::try_init_default();
+        let mut right = SideProtocol::new(MockHardware::new(), "right");
+        let mut left = SideProtocol::new(MockHardware::new(), "left");
+        for i in 0..20 {
+            right.queue_event(key_event(i)).await.unwrap();
+            communicate(&mut right, &mut left).await;
+        }
+        for i in 0..7 {
+            left.queue_event(key_event(i)).await.unwrap();
+            communicate(&mut right, &mut left).await;
+        }
+        assert!(is_stable(&right));
+        let stats = right.stats();
+
+        // The left side restarts, its first announce gets lost
+        left = SideProtocol::new(MockHardware::new(), "left");
+        left.announce_reset().await.unwrap();
+        left.hw.queue.clear();
+        advance(&mut right, &mut left, RETRANSMIT_TIMEOUT_MS).await;
+        communicate(&mut right, &mut left).await;
+        assert_eq!(right.hw.peer_resets, 1);
+        assert_eq!(right.stats().peer_resets, 1);
+        assert!(is_stable(&right));
+        assert!(is_stable(&left));
+
+        // Both sides start over, without any retransmit
+        for i in 0..4 {
+            right.queue_event(key_event(i)).await.unwrap();
+            left.queue_event(key_event(i + 4)).await.unwrap();
+            communicate(&mut right, &mut left).await;
+        }
+        assert_eq!(left.hw.events, (0..4).map(key_event).collect::<Vec<_>>());
+        assert_eq!(
+            &right.hw.events[7..],
+            (4..8).map(key_event).collect::<Vec<_>>()
+        );
+        assert_eq!(right.stats().sid_gaps, stats.sid_gaps);
+        assert_eq!(right.stats().crc_errors, stats.crc_errors);
+        assert_eq!(left.stats().sid_gaps, 0);
+        assert_eq!(right.stats().retransmits_served, stats.retransmits_served);
+    }
+
+    #[tokio::test]
+    async fn test_forged_reset() {
+        let _ = lovely_env_logger::try_init_default();
+        let mut right = SideProtocol::new(MockHardware::new(), "right");
+        let mut left = SideProtocol::new(MockHardware::new(), "left");
+        for i in 0..10 {
+            right.queue_event(key_event(i)).await.unwrap();
+            left.queue_event(key_event(i)).await.unwrap();
+            communicate(&mut right, &mut left).await;
+        }
+        assert!(is_stable(&right));
+
+        // A corrupted word passes the check bits as a reset: the left side
+        // starts over alone, its sequence ids are too far ahead for the
+        // right one
+        let msg = serialize(Event::Reset, Sid::new(7)).unwrap();
+        left.receive(msg).await;
+        right.queue_event(key_event(10)).await.unwrap();
+        left.queue_event(key_event(11)).await.unwrap();
+        for _ in 0..2 * MAX_OUT_OF_SYNC {
+            advance(&mut right, &mut left, RETRANSMIT_TIMEOUT_MS).await;
+            communicate(&mut right, &mut left).await;
+        }
+        assert_eq!(right.stats().restarts + left.stats().restarts, 1);
+        assert!(is_stable(&right));
+        assert!(is_stable(&left));
+
+        // Both sides are in sync again
+        let (nb_right, nb_left) = (right.hw.events.len(), left.hw.events.len());
+        for i in 0..4 {
+            right.queue_event(key_event(i)).await.unwrap();
+            left.queue_event(key_event(i + 4)).await.unwrap();
+            communicate(&mut right, &mut left).await;
+        }
+        assert_eq!(
+            &left.hw.events[nb_left..],
+            (0..4).map(key_event).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            &right.hw.events[nb_right..],
+            (4..8).map(key_event).collect::<Vec<_>>()
+        );
+    }
+
+    /// Connect two sides able to change the link speed, the right one leading
+    async fn connect_link_speed() -> (SideProtocol<MockHardware>, SideProtocol<MockHardware>) {
+        let mut right = SideProtocol::new(MockHardware::new(), "right");
+        let mut left = SideProtocol::new(MockHardware::new(), "left");
+        right.set_capabilities(CAP_LINK_SPEED);
+        left.set_capabilities(CAP_LINK_SPEED);
+        right.enable_handshake(0xdead_beef);
+        left.enable_handshake(0xdead_beef);
+        right.lead_link_speed();
+        right.queue_event(Event::Ping).await.unwrap();
+        communicate(&mut right, &mut left).await;
+        (right, left)
+    }
+
+    /// Let the time pass, with a ping every 100ms
+    async fn ping_for(
+        right: &mut SideProtocol<MockHardware>,
+        left: &mut SideProtocol<MockHardware>,
+        ms: u64,
+    ) {
+        for _ in 0..ms / 100 {
+            right.queue_event(Event::Ping).await.unwrap();
+            communicate(right, left).await;
+            advance(right, left, 100).await;
+            communicate(right, left).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_link_speed_up() {
+        let _ = lovely_env_logger::try_init_default();
+        let (mut right, mut left) = connect_link_speed().await;
+        assert_eq!(right.capabilities(), CAP_LINK_SPEED);
+        assert_eq!(right.link_speed(), LINK_SPEEDS[0]);
+
+        // The speed is stepped up while there are no errors
+        ping_for(&mut right, &mut left, LINK_SPEED_UP_MS + 200).await;
+        assert_eq!(right.link_speed(), LINK_SPEEDS[1]);
+        assert_eq!(left.link_speed(), LINK_SPEEDS[1]);
+        ping_for(&mut right, &mut left, LINK_SPEED_UP_MS + 200).await;
+        assert_eq!(right.link_speed(), LINK_SPEEDS[2]);
+        assert_eq!(left.link_speed(), LINK_SPEEDS[2]);
+        assert_eq!(right.hw.speeds, LINK_SPEEDS[1..3]);
+        assert_eq!(left.hw.speeds, LINK_SPEEDS[1..3]);
+        assert_eq!(right.stats().link_speed, LINK_SPEEDS[2]);
+        assert!(is_stable(&right));
+        assert!(is_stable(&left));
+
+        // The link goes down: back to the first speed
+        advance(&mut right, &mut left, LINK_DOWN_MS).await;
+        assert_eq!(right.link_speed(), LINK_SPEEDS[0]);
+        assert_eq!(left.link_speed(), LINK_SPEEDS[0]);
+    }
+
+    #[tokio::test]
+    async fn test_link_speed_down() {
+        let _ = lovely_env_logger::try_init_default();
+        let (mut right, mut left) = connect_link_speed().await;
+        ping_for(&mut right, &mut left, LINK_SPEED_UP_MS + 200).await;
+        assert_eq!(right.link_speed(), LINK_SPEEDS[1]);
+
+        // CRC failures rise: back to the previous speed, for good
+        for _ in 0..LINK_SPEED_DOWN_ERRORS {
+            let msg = serialize(Event::Ping, left.next_tx_sid).unwrap();
+            right.receive(msg ^ 0x0001_0101).await;
+            communicate(&mut right, &mut left).await;
+        }
+        advance(&mut right, &mut left, 1).await;
+        communicate(&mut right, &mut left).await;
+        assert_eq!(right.link_speed(), LINK_SPEEDS[0]);
+        assert_eq!(left.link_speed(), LINK_SPEEDS[0]);
+        ping_for(&mut right, &mut left, LINK_SPEED_UP_MS + 200).await;
+        assert_eq!(right.link_speed(), LINK_SPEEDS[0]);
+        assert_eq!(
+            left.hw.speeds,
+            LINK_SPEEDS[..2].iter().rev().copied().collect::<Vec<_>>()
+        );
+        assert!(is_stable(&right));
+    }
+
+    #[tokio::test]
+    async fn test_link_speed_probe() {
+        let _ = lovely_env_logger::try_init_default();
+        let (mut right, mut left) = connect_link_speed().await;
+        ping_for(&mut right, &mut left, LINK_SPEED_UP_MS - 100).await;
+
+        // Nothing is heard after the switch: both sides go back
+        advance(&mut right, &mut left, 100).await;
+        let msg = right.hw.queue.pop_back().unwrap();
+        assert!(matches!(deserialize(msg), Ok((Event::SetLinkSpeed(1), _))));
+        left.receive(msg).await;
+        let msg = left.hw.queue.pop_back().unwrap();
+        right.receive(msg).await;
+        assert_eq!(right.link_speed(), LINK_SPEEDS[1]);
+        assert_eq!(left.link_speed(), LINK_SPEEDS[1]);
+        right.hw.queue.clear();
+        advance(&mut right, &mut left, LINK_SPEED_PROBE_MS).await;
+        assert_eq!(right.link_speed(), LINK_SPEEDS[0]);
+        assert_eq!(left.link_speed(), LINK_SPEEDS[0]);
+        right.hw.queue.clear();
+        left.hw.queue.clear();
+
+        // That speed is not tried again
+        ping_for(&mut right, &mut left, LINK_SPEED_UP_MS + 200).await;
+        assert_eq!(right.link_speed(), LINK_SPEEDS[0]);
+        assert_eq!(
+            right.hw.speeds,
+            LINK_SPEEDS[..2].iter().rev().copied().collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_extended_frame() {
+        let _ = lovely_env_logger::try_init_default();
+        let hw_right = MockHardware::new();
+        let hw_left = MockHardware::new();
+        let mut right = SideProtocol::new(hw_right, "right");
+        let mut left = SideProtocol::new(hw_left, "left");
+
+        right.queue_event(Event::Press(0, 1)).await.unwrap();
+        right
+            .queue_event(Event::SeedRng32(0x1234_5678))
+            .await
+            .unwrap();
+        right.queue_event(Event::Release(0, 1)).await.unwrap();
+        assert_eq!(right.hw.msg_sent, 5);
+        communicate(&mut right, &mut left).await;
+        assert_eq!(
+            left.hw.events,
+            vec![
+                Event::Press(0, 1),
+                Event::SeedRng32(0x1234_5678),
+                Event::Release(0, 1)
+            ]
+        );
+        assert!(is_stable(&right));
+        assert_eq!(left.stats().received, 3);
+    }
+
+    #[tokio::test]
+    async fn test_extended_frame_corrupted() {
+        let _ = lovely_env_logger::try_init_default();
+        let hw_right = MockHardware::new();
+        let hw_left = MockHardware::new();
+        let mut right = SideProtocol::new(hw_right, "right");
+        let mut left = SideProtocol::new(hw_left, "left");
+
+        right.queue_event(Event::SeedRng32(0xcafe)).await.unwrap();
+        right.queue_event(Event::Press(2, 3)).await.unwrap();
+        // The last continuation word is corrupted
+        let header = right.hw.queue.pop_back().unwrap();
+        let first = right.hw.queue.pop_back().unwrap();
+        let last = right.hw.queue.pop_back().unwrap();
+        left.receive(header).await;
+        left.receive(first).await;
+        left.receive(last ^ 0x0001_0101).await;
+        assert_eq!(left.stats().crc_errors, 1);
+        communicate(&mut right, &mut left).await;
+        assert_eq!(
+            left.hw.events,
+            vec![Event::SeedRng32(0xcafe), Event::Press(2, 3)]
+        );
+        assert!(is_stable(&right));
+        assert!(left.rx_errors.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_half_duplex() {
+        let _ = lovely_env_logger::try_init_default();
+        let mut right = SideProtocol::new(MockHardware::new(), "right");
+        let mut left = SideProtocol::new(MockHardware::new(), "left");
+        right.set_half_duplex(HalfDuplex::Leader);
+        left.set_half_duplex(HalfDuplex::Follower);
+
+        // The follower holds its ack and its own events until polled
+        right.queue_event(Event::Press(1, 2)).await.unwrap();
+        communicate(&mut right, &mut left).await;
+        left.queue_event(Event::Release(0, 0)).await.unwrap();
+        assert_eq!(left.hw.events, vec![Event::Press(1, 2)]);
+        assert!(left.hw.queue.is_empty());
+        assert!(!is_stable(&right));
+
+        // The leader gives the turn, the follower talks then gives it back
+        advance(&mut right, &mut left, 1).await;
+        assert_eq!(
+            deserialize(right.hw.queue.back().copied().unwrap()),
+            Ok((Event::Over, Sid::new(1)))
+        );
+        communicate(&mut right, &mut left).await;
+        assert_eq!(right.hw.events, vec![Event::Release(0, 0)]);
+        assert!(is_stable(&right));
+        assert!(is_stable(&left));
+
+        // The turn given is lost: the leader takes it back after a while
+        advance(&mut right, &mut left, 1).await;
+        right.hw.queue.pop_back().unwrap();
+        right.queue_event(Event::Press(2, 2)).await.unwrap();
+        assert!(right.hw.queue.is_empty());
+        advance(&mut right, &mut left, TURN_TIMEOUT_MS - 1).await;
+        assert!(right.hw.queue.is_empty());
+        advance(&mut right, &mut left, 1).await;
+        communicate(&mut right, &mut left).await;
+        assert_eq!(left.hw.events, vec![Event::Press(1, 2), Event::Press(2, 2)]);
+        assert_eq!(right.window_stats().outbox_drops, 0);
+    }
+
+    #[tokio::test]
+    async fn test_half_duplex_link_speed() {
+        let _ = lovely_env_logger::try_init_default();
+        let (mut right, mut left) = connect_link_speed().await;
+        right.set_half_duplex(HalfDuplex::Leader);
+        left.set_half_duplex(HalfDuplex::Follower);
+
+        // Each side switches once the words of its turn are sent, the
+        // leader polling as often as the firmware does
+        for _ in 0..(LINK_SPEED_UP_MS + 200) / 100 {
+            right.queue_event(Event::Ping).await.unwrap();
+            for _ in 0..20 {
+                advance(&mut right, &mut left, 5).await;
+                communicate(&mut right, &mut left).await;
+            }
+        }
+        assert_eq!(right.link_speed(), LINK_SPEEDS[1]);
+        assert_eq!(left.link_speed(), LINK_SPEEDS[1]);
+        assert_eq!(right.hw.speeds, LINK_SPEEDS[1..2]);
+        assert_eq!(left.hw.speeds, LINK_SPEEDS[1..2]);
+        assert!(is_stable(&right));
+        assert!(is_stable(&left));
+    }
+
+    #[tokio::test]
+    async fn test_clock_moving_on_every_read() {
+        let _ = lovely_env_logger::try_init_default();
+        let (mut right, mut left) = connect_link_speed().await;
+        right.set_half_duplex(HalfDuplex::Leader);
+        left.set_half_duplex(HalfDuplex::Follower);
+        // As on the firmware, time passes between two reads of the clock
+        right.hw.clock_step = 1;
+        left.hw.clock_step = 1;
+
+        // Speed changes and turns stamp the time they are sent
+        for _ in 0..(LINK_SPEED_UP_MS + 200) / 100 {
+            right.queue_event(Event::Press(0, 1)).await.unwrap();
+            right.queue_event(Event::Release(0, 1)).await.unwrap();
+            for _ in 0..20 {
+                advance(&mut right, &mut left, 5).await;
+                communicate(&mut right, &mut left).await;
+            }
+        }
+        assert!(right.link_speed() > LINK_SPEEDS[0]);
+        assert_eq!(left.link_speed(), right.link_speed());
+
+        // So do the messages sent again after a timeout
+        for _ in 0..10 {
+            right.queue_event(Event::Press(0, 1)).await.unwrap();
+            advance(&mut right, &mut left, 5).await;
+            right.hw.queue.pop_front();
+            for _ in 0..20 {
+                advance(&mut right, &mut left, RETRANSMIT_TIMEOUT_MS).await;
+                communicate(&mut right, &mut left).await;
+            }
+        }
+        assert!(is_stable(&right));
+        assert!(is_stable(&left));
+        assert!(left.rx_errors.is_empty());
+    }
+
+    #[cfg(feature = "journal")]
+    #[tokio::test]
+    async fn test_journal() {
+        use crate::journal::{Decoded, Direction};
+        let _ = lovely_env_logger::try_init_default();
+        let mut right = SideProtocol::new(MockHardware::new(), "right");
+        let mut left = SideProtocol::new(MockHardware::new(), "left");
+
+        right.queue_event(Event::Press(1, 2)).await.unwrap();
+        let msg = right.hw.queue.pop_back().unwrap();
+        left.receive(msg ^ 0x0001_0101).await;
+        communicate(&mut right, &mut left).await;
+        let journal: Vec<_> = left
+            .journal()
+            .iter()
+            .map(|e| (e.direction, e.decoded))
+            .collect();
+        assert_eq!(
+            journal,
+            [
+                (Direction::Received, Decoded::Invalid),
+                (
+                    Direction::Sent,
+                    Decoded::Event(Event::Retransmit(Sid::new(0)), Sid::new(0))
+                ),
+                (
+                    Direction::Received,
+                    Decoded::Event(Event::Press(1, 2), Sid::new(0))
+                ),
+                (
+                    Direction::Sent,
+                    Decoded::Event(Event::Ack(Sid::new(0)), Sid::new(0))
+                ),
+            ]
+        );
+        assert_eq!(left.journal().iter().next().unwrap().msg, msg ^ 0x0001_0101);
+    }
 }
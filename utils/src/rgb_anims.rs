@@ -203,6 +203,11 @@ impl RgbAnim {
         }
     }
 
+    /// Seed the PRNG, so that both sides generate the same colors
+    pub fn set_seed(&mut self, seed: u32) {
+        self.prng = XorShift32::new(seed);
+    }
+
     /// Get the LED index for a key
     fn get_led_index(&self, i: u8, j: u8) -> usize {
         if self.is_right {
@@ -333,6 +338,11 @@ impl RgbAnim {
         self.animation
     }
 
+    /// Animation set, regardless of a temporary solid color
+    pub fn animation(&self) -> RgbAnimType {
+        self.saved_animation.unwrap_or(self.animation)
+    }
+
     /// Set the Animation
     pub fn set_animation(&mut self, animation: RgbAnimType) {
         info!("Set animation: {:?}", animation);
@@ -391,4 +401,14 @@ mod tests {
             assert_eq!(*t, t2);
         }
     }
+
+    #[test]
+    fn test_animation_under_solid_color() {
+        let mut anim = RgbAnim::new(false, 42);
+        anim.set_animation(RgbAnimType::Wheel);
+        anim.temporarily_solid_color(3);
+        assert_eq!(anim.animation(), RgbAnimType::Wheel);
+        anim.restore_animation();
+        assert_eq!(anim.animation(), RgbAnimType::Wheel);
+    }
 }
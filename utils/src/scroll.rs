@@ -0,0 +1,502 @@
+//! Scrolling with the ball
+//!
+//! The movements of the ball are accumulated into wheel and pan units, the
+//! counts left over being kept for the next movement, so that slow motions
+//! still scroll.  Hosts supporting high-resolution scrolling set a resolution
+//! multiplier through a feature report: each unit is then a fraction of a
+//! detent.
+//!
+//! Two optional behaviours smooth drag-scrolling further:
+//! - axis locking: once a gesture has travelled far enough, it only scrolls
+//!   along its dominant axis, so that diagonal jitter does not make scrolling
+//!   wander;
+//! - inertia: when the ball stops, scrolling goes on at the speed of the end
+//!   of the gesture, slowing down until it stops.
+
+use crate::mouse_move::MouseMove;
+
+/// Units per detent when the host enables the resolution multiplier
+pub const HIRES_MULTIPLIER: i32 = 120;
+
+/// Fixed point unit of the speeds
+pub const SPEED_UNIT: i32 = 256;
+
+/// A gesture ends when the ball has not moved for this long, in ms
+pub const GESTURE_TIMEOUT_MS: u64 = 50;
+
+/// Period at which inertia scrolls, in ms
+pub const INERTIA_INTERVAL_MS: u64 = 10;
+
+/// Scrolling going on after the ball stops
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Inertia {
+    /// Share of the speed kept every `INERTIA_INTERVAL_MS`, out of
+    /// `SPEED_UNIT`
+    pub decay: i32,
+    /// Speed under which scrolling stops, in counts per
+    /// `INERTIA_INTERVAL_MS`, in fixed point
+    pub min_speed: i32,
+}
+
+/// How much the ball moves to scroll
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ScrollConfig {
+    /// Counts of the sensor per detent of the vertical wheel
+    pub vertical_divisor: i32,
+    /// Counts of the sensor per detent of the horizontal wheel
+    pub horizontal_divisor: i32,
+    /// Counts a gesture travels before being locked to its dominant axis,
+    /// `None` to scroll along both axes
+    pub axis_lock: Option<i32>,
+    /// Keep scrolling after the ball stops
+    pub inertia: Option<Inertia>,
+}
+
+/// Axis a gesture is locked to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+enum Axis {
+    /// Vertical wheel
+    Vertical,
+    /// Horizontal pan
+    Horizontal,
+}
+
+/// Movements of the ball without a pause longer than `GESTURE_TIMEOUT_MS`
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+struct Gesture {
+    /// Time of the last movement, in ms
+    last_ms: u64,
+    /// Counts travelled while the axis is not locked yet
+    travel: (i32, i32),
+    /// Axis the gesture is locked to
+    axis: Option<Axis>,
+    /// Smoothed speed, in counts per `INERTIA_INTERVAL_MS`, in fixed point
+    speed: (i32, i32),
+}
+
+/// Scrolling by inertia
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+struct Coast {
+    /// Time of the next step, in ms
+    next_ms: u64,
+    /// Speed, in counts per `INERTIA_INTERVAL_MS`, in fixed point
+    speed: (i32, i32),
+    /// Fractions of counts not yet scrolled, in fixed point
+    carry: (i32, i32),
+}
+
+/// Accumulates the movements of the ball into wheel and pan units
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Scroll {
+    /// Counts per detent, axis locking and inertia
+    config: ScrollConfig,
+    /// Units per detent, vertically and horizontally
+    multipliers: (i32, i32),
+    /// Counts not yet scrolled, times the multipliers
+    remainder: (i32, i32),
+    /// Gesture in progress
+    gesture: Option<Gesture>,
+    /// Scrolling by inertia in progress
+    coast: Option<Coast>,
+}
+
+/// Resolution multipliers set by the host, vertical then horizontal, from the
+/// feature report
+/// Each one takes 2 bits: 0 for a unit per detent, 1 for `HIRES_MULTIPLIER`.
+pub fn resolution_multipliers(feature: u8) -> (i32, i32) {
+    let multiplier = |bits: u8| {
+        if bits & 0b11 != 0 {
+            HIRES_MULTIPLIER
+        } else {
+            1
+        }
+    };
+    (multiplier(feature), multiplier(feature >> 2))
+}
+
+/// Units of a wheel for @counts more counts, the rest being kept in
+/// @remainder
+fn units(counts: i32, multiplier: i32, divisor: i32, remainder: &mut i32) -> i8 {
+    let divisor = divisor.max(1);
+    let total = counts.saturating_mul(multiplier).saturating_add(*remainder);
+    let units = (total / divisor).clamp(i8::MIN as i32 + 1, i8::MAX as i32);
+    // What does not fit in a report is scrolled by the next ones
+    *remainder = total - units * divisor;
+    units as i8
+}
+
+impl Scroll {
+    /// Create an accumulator, a unit per detent until the host sets the
+    /// resolution multipliers
+    pub const fn new(config: ScrollConfig) -> Self {
+        Self {
+            config,
+            multipliers: (1, 1),
+            remainder: (0, 0),
+            gesture: None,
+            coast: None,
+        }
+    }
+
+    /// Counts per detent, axis locking and inertia
+    pub fn config(&self) -> ScrollConfig {
+        self.config
+    }
+
+    /// Change how the ball scrolls
+    pub fn set_config(&mut self, config: ScrollConfig) {
+        self.config = config;
+        self.reset();
+    }
+
+    /// Set the units per detent, vertically and horizontally
+    pub fn set_multipliers(&mut self, (vertical, horizontal): (i32, i32)) {
+        self.multipliers = (vertical.max(1), horizontal.max(1));
+        self.reset();
+    }
+
+    /// Units per detent, vertically and horizontally
+    pub fn multipliers(&self) -> (i32, i32) {
+        self.multipliers
+    }
+
+    /// Forget the counts not yet scrolled, end the gesture and stop scrolling
+    /// by inertia
+    pub fn reset(&mut self) {
+        self.remainder = (0, 0);
+        self.gesture = None;
+        self.coast = None;
+    }
+
+    /// Whether scrolling goes on by inertia
+    pub fn is_coasting(&self) -> bool {
+        self.coast.is_some()
+    }
+
+    /// Accumulate a movement of the ball, at @now_ms
+    /// Gives the wheel and pan units to report.  Moving the ball down scrolls
+    /// down, moving it right scrolls right.
+    pub fn accumulate(&mut self, MouseMove { dx, dy }: MouseMove, now_ms: u64) -> (i8, i8) {
+        self.end_gesture(now_ms);
+        if dx == 0 && dy == 0 {
+            return self.scroll(0, 0);
+        }
+        // Touching the ball stops inertia
+        self.coast = None;
+        let gesture = self.gesture.get_or_insert(Gesture {
+            last_ms: now_ms.saturating_sub(INERTIA_INTERVAL_MS),
+            travel: (0, 0),
+            axis: None,
+            speed: (0, 0),
+        });
+
+        let (mut dx, mut dy) = (dx as i32, dy as i32);
+        if let Some(threshold) = self.config.axis_lock {
+            if gesture.axis.is_none() {
+                // Hold the movements until the dominant axis is known
+                gesture.travel.0 = gesture.travel.0.saturating_add(dx);
+                gesture.travel.1 = gesture.travel.1.saturating_add(dy);
+                let (tx, ty) = gesture.travel;
+                if tx.unsigned_abs().saturating_add(ty.unsigned_abs()) < threshold as u32 {
+                    (dx, dy) = (0, 0);
+                } else {
+                    gesture.axis = Some(if tx.abs() > ty.abs() {
+                        Axis::Horizontal
+                    } else {
+                        Axis::Vertical
+                    });
+                    (dx, dy) = (tx, ty);
+                    gesture.travel = (0, 0);
+                }
+            }
+            match gesture.axis {
+                Some(Axis::Vertical) => dx = 0,
+                Some(Axis::Horizontal) => dy = 0,
+                None => {}
+            }
+        }
+
+        // Speed over the last interval, averaged with the previous ones
+        let elapsed = now_ms
+            .saturating_sub(gesture.last_ms)
+            .clamp(1, GESTURE_TIMEOUT_MS) as i32;
+        let per_interval =
+            |d: i32| d.saturating_mul(SPEED_UNIT * INERTIA_INTERVAL_MS as i32) / elapsed;
+        gesture.speed = (
+            (gesture.speed.0 / 2).saturating_add(per_interval(dx) / 2),
+            (gesture.speed.1 / 2).saturating_add(per_interval(dy) / 2),
+        );
+        gesture.last_ms = now_ms;
+        self.scroll(dx, dy)
+    }
+
+    /// Scroll by inertia, when the ball has stopped, at @now_ms
+    /// To be called regularly, at least every `INERTIA_INTERVAL_MS`.  Gives
+    /// the wheel and pan units to report.
+    pub fn coast(&mut self, now_ms: u64) -> (i8, i8) {
+        self.end_gesture(now_ms);
+        let Some(mut coast) = self.coast else {
+            return (0, 0);
+        };
+        let Some(inertia) = self.config.inertia else {
+            self.coast = None;
+            return (0, 0);
+        };
+        let (mut dx, mut dy) = (0i32, 0i32);
+        while now_ms >= coast.next_ms {
+            let (sx, sy) = coast.speed;
+            if sx.unsigned_abs().max(sy.unsigned_abs()) < inertia.min_speed as u32 {
+                self.coast = None;
+                return self.scroll(dx, dy);
+            }
+            coast.carry = (coast.carry.0 + sx, coast.carry.1 + sy);
+            dx = dx.saturating_add(coast.carry.0 / SPEED_UNIT);
+            dy = dy.saturating_add(coast.carry.1 / SPEED_UNIT);
+            coast.carry = (coast.carry.0 % SPEED_UNIT, coast.carry.1 % SPEED_UNIT);
+            coast.speed = (
+                (sx as i64 * inertia.decay as i64 / SPEED_UNIT as i64) as i32,
+                (sy as i64 * inertia.decay as i64 / SPEED_UNIT as i64) as i32,
+            );
+            coast.next_ms += INERTIA_INTERVAL_MS;
+        }
+        self.coast = Some(coast);
+        self.scroll(dx, dy)
+    }
+
+    /// End the gesture if the ball has not moved for long enough, starting
+    /// to scroll by inertia
+    fn end_gesture(&mut self, now_ms: u64) {
+        let Some(gesture) = self.gesture else {
+            return;
+        };
+        if now_ms.saturating_sub(gesture.last_ms) < GESTURE_TIMEOUT_MS {
+            return;
+        }
+        self.gesture = None;
+        if self.config.inertia.is_some() && gesture.speed != (0, 0) {
+            self.coast = Some(Coast {
+                next_ms: now_ms,
+                speed: gesture.speed,
+                carry: (0, 0),
+            });
+        }
+    }
+
+    /// Turn counts of the sensor into wheel and pan units
+    fn scroll(&mut self, dx: i32, dy: i32) -> (i8, i8) {
+        let wheel = units(
+            dy.saturating_neg(),
+            self.multipliers.0,
+            self.config.vertical_divisor,
+            &mut self.remainder.0,
+        );
+        let pan = units(
+            dx,
+            self.multipliers.1,
+            self.config.horizontal_divisor,
+            &mut self.remainder.1,
+        );
+        (wheel, pan)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: ScrollConfig = ScrollConfig {
+        vertical_divisor: 16,
+        horizontal_divisor: 32,
+        axis_lock: None,
+        inertia: None,
+    };
+
+    const INERTIA: Inertia = Inertia {
+        decay: SPEED_UNIT * 3 / 4,
+        min_speed: SPEED_UNIT,
+    };
+
+    /// Swipe up, recorded on the sensor, the ball drifting to the right
+    const SWIPE_UP: [(i16, i16); 12] = [
+        (1, -3),
+        (2, -9),
+        (-1, -14),
+        (3, -22),
+        (4, -25),
+        (2, -31),
+        (5, -28),
+        (3, -24),
+        (4, -17),
+        (1, -12),
+        (2, -6),
+        (1, -2),
+    ];
+
+    /// Flick to the left, recorded on the sensor, with some vertical jitter
+    const FLICK_LEFT: [(i16, i16); 6] =
+        [(-12, 2), (-35, -3), (-60, 4), (-72, 1), (-70, -2), (-66, 3)];
+
+    /// Sum of the units reported for the movements
+    fn scroll(scroll: &mut Scroll, moves: &[(i16, i16)]) -> (i32, i32) {
+        moves.iter().fold((0, 0), |(w, p), (dx, dy)| {
+            let (wheel, pan) = scroll.accumulate(MouseMove::new(*dx, *dy), 0);
+            (w + wheel as i32, p + pan as i32)
+        })
+    }
+
+    /// Sum of the units reported for the movements, one every 10ms from
+    /// @start_ms, then of the units scrolled by inertia until @end_ms
+    fn replay(
+        scroll: &mut Scroll,
+        moves: &[(i16, i16)],
+        start_ms: u64,
+        end_ms: u64,
+    ) -> ((i32, i32), (i32, i32)) {
+        let mut moved = (0, 0);
+        let mut now = start_ms;
+        for (dx, dy) in moves {
+            let (wheel, pan) = scroll.accumulate(MouseMove::new(*dx, *dy), now);
+            moved = (moved.0 + wheel as i32, moved.1 + pan as i32);
+            now += 10;
+        }
+        let mut coasted = (0, 0);
+        while now < end_ms {
+            let (wheel, pan) = scroll.coast(now);
+            coasted = (coasted.0 + wheel as i32, coasted.1 + pan as i32);
+            now += 1;
+        }
+        (moved, coasted)
+    }
+
+    #[test]
+    fn test_multipliers() {
+        assert_eq!(resolution_multipliers(0b0000), (1, 1));
+        assert_eq!(resolution_multipliers(0b0001), (HIRES_MULTIPLIER, 1));
+        assert_eq!(resolution_multipliers(0b0100), (1, HIRES_MULTIPLIER));
+        assert_eq!(
+            resolution_multipliers(0b0101),
+            (HIRES_MULTIPLIER, HIRES_MULTIPLIER)
+        );
+    }
+
+    #[test]
+    fn test_slow_motion() {
+        // Small movements add up instead of being dropped
+        let mut s = Scroll::new(CONFIG);
+        assert_eq!(scroll(&mut s, &[(0, 4); 3]), (0, 0));
+        assert_eq!(scroll(&mut s, &[(0, 4)]), (-1, 0));
+        assert_eq!(scroll(&mut s, &[(8, 0); 3]), (0, 0));
+        assert_eq!(scroll(&mut s, &[(8, 0)]), (0, 1));
+        assert_eq!(scroll(&mut s, &[(0, -5); 16]), (5, 0));
+    }
+
+    #[test]
+    fn test_both_axes() {
+        let mut s = Scroll::new(CONFIG);
+        assert_eq!(scroll(&mut s, &[(-64, 32)]), (-2, -2));
+        assert_eq!(scroll(&mut s, &[(40, -40), (24, -8)]), (3, 2));
+
+        // Without axis locking, the drift scrolls too
+        let mut s = Scroll::new(ScrollConfig {
+            horizontal_divisor: 16,
+            ..CONFIG
+        });
+        assert_eq!(replay(&mut s, &SWIPE_UP, 0, 200), ((12, 1), (0, 0)));
+    }
+
+    #[test]
+    fn test_hires() {
+        let mut s = Scroll::new(CONFIG);
+        s.set_multipliers(resolution_multipliers(0b0101));
+        // 120 units per detent of 16 counts: 7.5 units per count
+        assert_eq!(scroll(&mut s, &[(0, -1)]), (7, 0));
+        assert_eq!(scroll(&mut s, &[(0, -1)]), (8, 0));
+        assert_eq!(scroll(&mut s, &[(0, -16)]), (120, 0));
+        assert_eq!(scroll(&mut s, &[(32, 0)]), (0, 120));
+    }
+
+    #[test]
+    fn test_overflow() {
+        // What does not fit in a report goes in the next ones
+        let mut s = Scroll::new(CONFIG);
+        s.set_multipliers((HIRES_MULTIPLIER, 1));
+        assert_eq!(s.accumulate(MouseMove::new(0, -100), 0), (127, 0));
+        assert_eq!(scroll(&mut s, &[(0, 0); 10]), (750 - 127, 0));
+        for _ in 0..1000 {
+            assert_eq!(s.accumulate(MouseMove::new(0, i16::MIN), 0), (127, 0));
+        }
+        assert_eq!(s.accumulate(MouseMove::new(0, 0), 0), (127, 0));
+        s.reset();
+        assert_eq!(s.accumulate(MouseMove::new(0, 0), 0), (0, 0));
+    }
+
+    #[test]
+    fn test_axis_lock() {
+        let mut s = Scroll::new(ScrollConfig {
+            horizontal_divisor: 16,
+            axis_lock: Some(8),
+            ..CONFIG
+        });
+        // The drift to the right is dropped, the movements held before
+        // locking are not
+        assert_eq!(replay(&mut s, &SWIPE_UP, 0, 200), ((12, 0), (0, 0)));
+        assert_eq!(replay(&mut s, &FLICK_LEFT, 200, 400), ((0, -19), (0, 0)));
+
+        // Too short to be locked: nothing scrolls
+        assert_eq!(
+            replay(&mut s, &[(2, 1), (-1, 2)], 400, 500),
+            ((0, 0), (0, 0))
+        );
+
+        // Each gesture has its own axis
+        let ((_, pan), _) = replay(&mut s, &[(0, 10), (40, 0)], 500, 600);
+        assert_eq!(pan, 0);
+        let ((wheel, _), _) = replay(&mut s, &[(20, 0), (0, -40)], 600, 700);
+        assert_eq!(wheel, 0);
+    }
+
+    #[test]
+    fn test_inertia() {
+        let mut s = Scroll::new(ScrollConfig {
+            inertia: Some(INERTIA),
+            ..CONFIG
+        });
+        // A flick keeps scrolling the same way, slowing down until it stops
+        let ((_, moved), (_, coasted)) = replay(&mut s, &FLICK_LEFT, 0, 500);
+        assert_eq!(moved, -9);
+        assert!(coasted < -4);
+        assert!(!s.is_coasting());
+        assert_eq!(replay(&mut s, &[], 500, 1000), ((0, 0), (0, 0)));
+
+        // Touching the ball stops it
+        let _ = replay(&mut s, &FLICK_LEFT, 1000, 1110);
+        assert!(s.is_coasting());
+        let _ = s.accumulate(MouseMove::new(0, 1), 1110);
+        assert!(!s.is_coasting());
+        s.reset();
+
+        // Slowing the ball down before stopping leaves little to coast
+        let ((wheel, _), (coasted, _)) = replay(&mut s, &SWIPE_UP, 2000, 2500);
+        assert_eq!(wheel, 12);
+        assert!((0..=1).contains(&coasted));
+    }
+
+    #[test]
+    fn test_lock_and_inertia() {
+        let mut s = Scroll::new(ScrollConfig {
+            axis_lock: Some(8),
+            inertia: Some(INERTIA),
+            ..CONFIG
+        });
+        let ((wheel, moved), (no_wheel, coasted)) = replay(&mut s, &FLICK_LEFT, 0, 500);
+        assert_eq!((wheel, no_wheel), (0, 0));
+        assert_eq!(moved, -9);
+        assert!(coasted < -4);
+    }
+}
@@ -1,23 +1,52 @@
 //! Serialization and deserialization of key events
 
+#[cfg(feature = "fec")]
+use crate::fec;
+use crate::geometry::{Geometry, GEOMETRY};
+use crate::key_matrix::KeyMatrix;
+use crate::mouse_move::MouseMove;
 use crate::rgb_anims::RgbAnimType;
 
-use crate::sid::Sid;
+use crate::sid::{Sid, SID_MAX};
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum Event {
     Noop,
     Ping,
+    Reset,
+    Over,
     Retransmit(Sid),        // SidSize
     Ack(Sid),               // SidSize
-    Press(u8, u8),          // r: [0, 3], c: [0, 4]: 7 bits
-    Release(u8, u8),        // r: [0, 3], c: [0, 4]: 7 bits
+    AckUpTo(Sid),           // SidSize
+    Press(u8, u8),          // r, c: 4 bits each, within `GEOMETRY`
+    Release(u8, u8),        // r, c: 4 bits each, within `GEOMETRY`
     RgbAnim(RgbAnimType),   // 8 bits
     RgbAnimChangeLayer(u8), // 4 bits
     SeedRng(u8),            // 8 bits
+    Hello(u8, u8),          // field: [1, 11], nibble: 4 bits
+    SetLinkSpeed(u8),       // 4 bits
+    SensorCommand(u8),      // [1, 15]
+    SeedRng32(u32),         // extended: 2 words
+    MouseMove(MouseMove),   // extended: 2 words
+    KeySnapshot(KeyMatrix), // extended: 2 words
 }
 
+/// Priority class of an event, the first ones are sent first
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Priority {
+    /// Key presses and releases, key snapshots, mouse moves
+    Input = 0,
+    /// Protocol and state shared between the halves
+    State = 1,
+    /// RGB animations, can be dropped when superseded by a newer one
+    Cosmetic = 2,
+}
+
+/// Number of priority classes
+pub const NB_PRIORITIES: usize = 3;
+
 #[derive(Debug, PartialEq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum Error {
@@ -26,6 +55,66 @@ pub enum Error {
 }
 pub type Message = u32;
 
+/// Highest field of the `Hello` event
+/// Hello events share the tag of `Noop` and `Ping`, the field is stored in
+/// the upper 4 bits of the data.
+pub const HELLO_FIELD_MAX: u8 = 11;
+
+/// Maximum number of words in a frame, header included
+pub const MAX_FRAME_LEN: usize = 5;
+
+/// Flag set in the data of cumulative acks, next to the sequence id
+const ACK_UP_TO: u16 = 0x80;
+
+/// `Noop` tag with the upper 4 bits of the data set to this value: the lower
+/// 4 bits are the index of the link speed to switch to
+const LINK_SPEED: u16 = 0xc0;
+
+/// Highest command of the `SensorCommand` event
+/// Sensor commands share the tag of `Noop`, the upper 4 bits of the data being
+/// clear.
+pub const SENSOR_COMMAND_MAX: u8 = 0xf;
+
+/// Data of the `Reset` event, with the `Noop` tag
+const RESET: u16 = 0xf0;
+
+/// Data of the `Over` event, with the `Noop` tag
+const OVER: u16 = 0xd0;
+
+/// Header of extended frames: `Noop` tag with the upper 4 bits of the data
+/// set, the lower 4 bits are the kind of extended event
+const EXT_HEADER: u16 = 0xe0;
+/// Extended event kind: `SeedRng32`
+const EXT_SEED_RNG32: u8 = 0;
+/// Extended event kind: `MouseMove`
+const EXT_MOUSE_MOVE: u8 = 1;
+/// Extended event kind: `KeySnapshot`
+const EXT_KEY_SNAPSHOT: u8 = 2;
+
+/// Number of continuation words following the header of an extended frame
+fn ext_len(kind: u8) -> Option<usize> {
+    match kind {
+        EXT_SEED_RNG32 | EXT_MOUSE_MOVE | EXT_KEY_SNAPSHOT => Some(2),
+        _ => None,
+    }
+}
+
+/// Frame of one or more words, as sent on the serial line
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Frame {
+    /// Words of the frame, header first
+    words: [Message; MAX_FRAME_LEN],
+    /// Number of words used
+    len: usize,
+}
+
+impl Frame {
+    /// Words of the frame, header first
+    pub fn words(&self) -> &[Message] {
+        &self.words[..self.len]
+    }
+}
+
 impl Event {
     /// whether the event is a retransmit
     pub fn is_retransmit(&self) -> bool {
@@ -34,18 +123,105 @@ impl Event {
 
     /// whether the event is an ack
     pub fn is_ack(&self) -> bool {
-        matches!(self, Event::Ack(_))
+        matches!(self, Event::Ack(_) | Event::AckUpTo(_))
     }
 
     /// whether the event is needs a ack
     pub fn needs_ack(&self) -> bool {
-        !matches!(self, Event::Noop | Event::Ack(_) | Event::Retransmit(_))
+        !matches!(
+            self,
+            Event::Noop | Event::Ack(_) | Event::AckUpTo(_) | Event::Retransmit(_) | Event::Over
+        )
+    }
+
+    /// whether the event takes a sequence id
+    /// Acks, retransmit requests and the end of a turn are sent outside of
+    /// the sequence
+    pub fn is_sequenced(&self) -> bool {
+        !matches!(
+            self,
+            Event::Ack(_) | Event::AckUpTo(_) | Event::Retransmit(_) | Event::Over
+        )
+    }
+
+    /// Priority class of the event
+    pub fn priority(&self) -> Priority {
+        match self {
+            // A snapshot must not overtake the key events before it
+            Event::Press(_, _)
+            | Event::Release(_, _)
+            | Event::MouseMove(_)
+            | Event::KeySnapshot(_) => Priority::Input,
+            Event::RgbAnim(_) | Event::RgbAnimChangeLayer(_) => Priority::Cosmetic,
+            _ => Priority::State,
+        }
+    }
+
+    /// whether the event makes @other pointless to send
+    /// Only cosmetic events can be superseded, by one of the same kind
+    pub fn supersedes(&self, other: &Event) -> bool {
+        matches!(
+            (self, other),
+            (Event::RgbAnim(_), Event::RgbAnim(_))
+                | (Event::RgbAnimChangeLayer(_), Event::RgbAnimChangeLayer(_))
+        )
+    }
+
+    /// whether the event does not fit in a single word
+    pub fn is_extended(&self) -> bool {
+        matches!(
+            self,
+            Event::SeedRng32(_) | Event::MouseMove(_) | Event::KeySnapshot(_)
+        )
+    }
+
+    /// Kind and payload of an extended event, 16 bits per continuation word
+    fn ext_payload(&self) -> Option<(u8, [u16; MAX_FRAME_LEN - 1])> {
+        let mut payload = [0; MAX_FRAME_LEN - 1];
+        let kind = match self {
+            Event::SeedRng32(seed) => {
+                payload[0] = *seed as u16;
+                payload[1] = (*seed >> 16) as u16;
+                EXT_SEED_RNG32
+            }
+            Event::MouseMove(m) => {
+                let v = m.to_u32();
+                payload[0] = v as u16;
+                payload[1] = (v >> 16) as u16;
+                EXT_MOUSE_MOVE
+            }
+            Event::KeySnapshot(keys) => {
+                let v = keys.to_u32()?;
+                payload[0] = v as u16;
+                payload[1] = (v >> 16) as u16;
+                EXT_KEY_SNAPSHOT
+            }
+            _ => return None,
+        };
+        Some((kind, payload))
+    }
+
+    /// Build an extended event from its kind and payload
+    fn from_ext_payload(kind: u8, payload: &[u16]) -> Result<Event, Error> {
+        match kind {
+            EXT_SEED_RNG32 => Ok(Event::SeedRng32(
+                (payload[0] as u32) | ((payload[1] as u32) << 16),
+            )),
+            EXT_MOUSE_MOVE => Ok(Event::MouseMove(MouseMove::from_u32(
+                (payload[0] as u32) | ((payload[1] as u32) << 16),
+            ))),
+            EXT_KEY_SNAPSHOT => Ok(Event::KeySnapshot(KeyMatrix::from_u32(
+                (payload[0] as u32) | ((payload[1] as u32) << 16),
+            ))),
+            _ => Err(Error::Deserialization),
+        }
     }
 
     /// Convert the event to a u16
     /// The upper 5 bits are the sequence id
     /// Then are 3 bits for the event type
     /// The lower 8 bits are the event data
+    /// For extended events, this is the header of the frame
     pub fn to_u16(&self, sid: Sid) -> Result<u16, Error> {
         let sid = (sid.as_u16()) << 11;
         let (tag, data) = match self {
@@ -53,30 +229,65 @@ impl Event {
             Event::Ping => Ok((0b000, 0xff)),
             Event::Retransmit(err) => Ok((0b001, err.as_u16())),
             Event::Ack(ack) => Ok((0b010, ack.as_u16())),
-            Event::Press(r, c) if *r <= 3 && *c <= 9 => {
-                Ok((0b011, ((*r as u16) << 4) | (*c as u16)))
-            }
-            Event::Press(_, _) => Err(Error::Serialization),
-            Event::Release(r, c) if *r <= 3 && *c <= 9 => {
-                Ok((0b100, ((*r as u16) << 4) | (*c as u16)))
-            }
-            Event::Release(_, _) => Err(Error::Serialization),
+            Event::AckUpTo(ack) => Ok((0b010, ACK_UP_TO | ack.as_u16())),
+            Event::Press(r, c) => key_data(&GEOMETRY, *r, *c)
+                .map(|data| (0b011, data))
+                .ok_or(Error::Serialization),
+            Event::Release(r, c) => key_data(&GEOMETRY, *r, *c)
+                .map(|data| (0b100, data))
+                .ok_or(Error::Serialization),
             Event::RgbAnim(anim) => Ok((0b101, anim.to_u8()? as u16)),
             Event::RgbAnimChangeLayer(layer) => Ok((0b110, *layer as u16)),
             Event::SeedRng(seed) => Ok((0b111, *seed as u16)),
+            Event::Hello(f, n) if (1..=HELLO_FIELD_MAX).contains(f) && *n <= 0xf => {
+                Ok((0b000, ((*f as u16) << 4) | (*n as u16)))
+            }
+            Event::Hello(_, _) => Err(Error::Serialization),
+            Event::SetLinkSpeed(speed) if *speed <= 0xf => {
+                Ok((0b000, LINK_SPEED | (*speed as u16)))
+            }
+            Event::SetLinkSpeed(_) => Err(Error::Serialization),
+            Event::SensorCommand(cmd) if (1..=SENSOR_COMMAND_MAX).contains(cmd) => {
+                Ok((0b000, *cmd as u16))
+            }
+            Event::SensorCommand(_) => Err(Error::Serialization),
+            Event::Reset => Ok((0b000, RESET)),
+            Event::Over => Ok((0b000, OVER)),
+            Event::SeedRng32(_) | Event::MouseMove(_) | Event::KeySnapshot(_) => {
+                match self.ext_payload() {
+                    Some((kind, _)) => Ok((0b000, EXT_HEADER | kind as u16)),
+                    None => Err(Error::Serialization),
+                }
+            }
         }?;
         Ok(sid | (tag << 8) | data)
     }
 }
 
+/// Data of a key event: the row in the upper 4 bits, the column in the lower
+/// ones
+/// Gives `None` if the key is out of the matrix of @geometry.
+fn key_data(geometry: &Geometry, r: u8, c: u8) -> Option<u16> {
+    if geometry.contains(r, c) {
+        Some(((r as u16) << 4) | (c as u16))
+    } else {
+        None
+    }
+}
+
+/// Key of the data of a key event, see `key_data`
+fn data_key(geometry: &Geometry, data: u8) -> Option<(u8, u8)> {
+    let (r, c) = (data >> 4, data & 0xf);
+    if geometry.contains(r, c) {
+        Some((r, c))
+    } else {
+        None
+    }
+}
+
 /// Deserialize a key event from the serial line
 pub fn deserialize(bytes: Message) -> Result<(Event, Sid), Error> {
-    let crc = (bytes >> 16) as u16;
-    let computed_crc = crc16::State::<crc16::KERMIT>::calculate(&bytes.to_le_bytes()[0..2]);
-    if crc != computed_crc {
-        return Err(Error::Deserialization);
-    }
-    let bytes = bytes & 0xffff;
+    let bytes = checked(bytes)? as u32;
     let sid = Sid::from_u32_lsb(bytes >> 11);
     let tag = (bytes >> 8) & 0b111;
     let data = bytes & 0xff;
@@ -84,10 +295,32 @@ pub fn deserialize(bytes: Message) -> Result<(Event, Sid), Error> {
     match tag {
         0b000 if data == 0x00 => Ok((Event::Noop, sid)),
         0b000 if data == 0xff => Ok((Event::Ping, sid)),
-        0b001 => Ok((Event::Retransmit(Sid::from_u32_lsb(data)), sid)),
-        0b010 => Ok((Event::Ack(Sid::from_u32_lsb(data)), sid)),
-        0b011 => Ok((Event::Press((data >> 4) as u8, (data & 0xf) as u8), sid)),
-        0b100 => Ok((Event::Release((data >> 4) as u8, (data & 0xf) as u8), sid)),
+        0b000 if data == RESET as u32 => Ok((Event::Reset, sid)),
+        0b000 if data == OVER as u32 => Ok((Event::Over, sid)),
+        0b000 if data <= SENSOR_COMMAND_MAX as u32 => Ok((Event::SensorCommand(data as u8), sid)),
+        0b000 if data & 0xf0 == LINK_SPEED as u32 => {
+            Ok((Event::SetLinkSpeed((data & 0xf) as u8), sid))
+        }
+        0b000 if (1..=HELLO_FIELD_MAX as u32).contains(&(data >> 4)) => {
+            Ok((Event::Hello((data >> 4) as u8, (data & 0xf) as u8), sid))
+        }
+        // A sequence id only takes 5 bits, the others must be clear
+        0b001 if data <= SID_MAX.as_u16() as u32 => {
+            Ok((Event::Retransmit(Sid::from_u32_lsb(data)), sid))
+        }
+        0b010 if data <= SID_MAX.as_u16() as u32 => Ok((Event::Ack(Sid::from_u32_lsb(data)), sid)),
+        0b010 if data & !(ACK_UP_TO as u32) <= SID_MAX.as_u16() as u32 => {
+            Ok((Event::AckUpTo(Sid::from_u32_lsb(data & 0x7f)), sid))
+        }
+        // Keys out of the matrix would be out of the layout as well
+        0b011 => match data_key(&GEOMETRY, data as u8) {
+            Some((r, c)) => Ok((Event::Press(r, c), sid)),
+            None => Err(Error::Deserialization),
+        },
+        0b100 => match data_key(&GEOMETRY, data as u8) {
+            Some((r, c)) => Ok((Event::Release(r, c), sid)),
+            None => Err(Error::Deserialization),
+        },
         0b101 => Ok((Event::RgbAnim(RgbAnimType::from_u8(data as u8)?), sid)),
         0b110 => Ok((Event::RgbAnimChangeLayer(data as u8), sid)),
         0b111 => Ok((Event::SeedRng(data as u8), sid)),
@@ -96,21 +329,178 @@ pub fn deserialize(bytes: Message) -> Result<(Event, Sid), Error> {
 }
 
 /// Serialize a key event
+/// Extended events do not fit in a single message, see `serialize_frame`
 pub fn serialize(e: Event, sid: Sid) -> Result<Message, Error> {
+    if e.is_extended() {
+        return Err(Error::Serialization);
+    }
     let ser = e.to_u16(sid)?;
+    Ok(with_check(ser))
+}
+
+/// Append the CRC16 of the 16 lower bits in the upper bits
+#[cfg(not(feature = "fec"))]
+pub(crate) fn with_check(ser: u16) -> Message {
     let crc: u16 = crc16::State::<crc16::KERMIT>::calculate(&ser.to_le_bytes());
-    let bytes = (ser as u32) | ((crc as u32) << 16);
-    Ok(bytes)
+    (ser as u32) | ((crc as u32) << 16)
+}
+
+/// Check the CRC16 of a message and give back its 16 lower bits
+#[cfg(not(feature = "fec"))]
+fn checked(msg: Message) -> Result<u16, Error> {
+    let crc = (msg >> 16) as u16;
+    let computed_crc = crc16::State::<crc16::KERMIT>::calculate(&msg.to_le_bytes()[0..2]);
+    if crc != computed_crc {
+        return Err(Error::Deserialization);
+    }
+    Ok(msg as u16)
+}
+
+/// CRC16 of a continuation word
+/// It is seeded with the sequence id of the frame and the position of the
+/// word, so that a word cannot be mistaken for one of another frame.
+#[cfg(not(feature = "fec"))]
+fn continuation_crc(payload: u16, sid: Sid, index: usize) -> u16 {
+    let [lo, hi] = payload.to_le_bytes();
+    crc16::State::<crc16::KERMIT>::calculate(&[lo, hi, sid.as_u16() as u8, index as u8])
+}
+
+/// Build a continuation word
+#[cfg(not(feature = "fec"))]
+fn continuation_word(payload: u16, sid: Sid, index: usize) -> Message {
+    let crc = continuation_crc(payload, sid, index);
+    (payload as u32) | ((crc as u32) << 16)
+}
+
+/// Check a continuation word and give back its payload
+#[cfg(not(feature = "fec"))]
+fn continuation_payload(word: Message, sid: Sid, index: usize) -> Result<u16, Error> {
+    let payload = word as u16;
+    if (word >> 16) as u16 != continuation_crc(payload, sid, index) {
+        return Err(Error::Deserialization);
+    }
+    Ok(payload)
+}
+
+/// Append the BCH check bits of the 16 lower bits in the upper bits
+#[cfg(feature = "fec")]
+pub(crate) fn with_check(ser: u16) -> Message {
+    fec::encode(ser)
+}
+
+/// Check a message, correcting up to `fec::MAX_CORRECTED` flipped bits, and
+/// give back its 16 lower bits
+#[cfg(feature = "fec")]
+fn checked(msg: Message) -> Result<u16, Error> {
+    fec::decode(msg)
+        .map(|(data, _)| data)
+        .ok_or(Error::Deserialization)
+}
+
+/// Masks of the check bits of continuation words, one per bit of the
+/// sequence id, then of the position of the word in the frame
+/// Any combination of them is at least 4 flipped bits away from a valid word,
+/// so that a word of another frame, or misplaced, is rejected even with a
+/// flipped bit.
+#[cfg(feature = "fec")]
+const CONTINUATION_MASKS: [u16; 8] = [
+    0x9b92, 0xc42f, 0x8e90, 0x3f59, 0x281e, 0xdb5c, 0x5def, 0xc606,
+];
+
+/// Mask of the check bits of a continuation word
+#[cfg(feature = "fec")]
+fn continuation_mask(sid: Sid, index: usize) -> Message {
+    let key = sid.as_u16() as usize | (index << 5);
+    let mask = CONTINUATION_MASKS
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| key & (1 << i) != 0)
+        .fold(0, |mask, (_, m)| mask ^ m);
+    (mask as u32) << 16
+}
+
+/// Build a continuation word
+#[cfg(feature = "fec")]
+fn continuation_word(payload: u16, sid: Sid, index: usize) -> Message {
+    fec::encode(payload) ^ continuation_mask(sid, index)
+}
+
+/// Check a continuation word, correcting up to `fec::MAX_CORRECTED` flipped
+/// bits, and give back its payload
+#[cfg(feature = "fec")]
+fn continuation_payload(word: Message, sid: Sid, index: usize) -> Result<u16, Error> {
+    checked(word ^ continuation_mask(sid, index))
+}
+
+/// Serialize an event into a frame
+/// Single-word events give a frame of one word, identical to `serialize`.
+/// Extended events give a header followed by continuation words, each one
+/// carrying 16 bits of payload and its own CRC16.
+pub fn serialize_frame(e: Event, sid: Sid) -> Result<Frame, Error> {
+    let mut frame = Frame {
+        words: [0; MAX_FRAME_LEN],
+        len: 1,
+    };
+    match e.ext_payload() {
+        None => frame.words[0] = serialize(e, sid)?,
+        Some((kind, payload)) => {
+            frame.words[0] = with_check(e.to_u16(sid)?);
+            let len = ext_len(kind).ok_or(Error::Serialization)?;
+            for (i, p) in payload.iter().take(len).enumerate() {
+                frame.words[i + 1] = continuation_word(*p, sid, i + 1);
+            }
+            frame.len = len + 1;
+        }
+    }
+    Ok(frame)
+}
+
+/// Number of words of the frame starting with this message
+/// This is 1 unless the message is the header of an extended frame
+pub fn frame_len(header: Message) -> usize {
+    let Ok(header) = checked(header) else {
+        return 1;
+    };
+    let tag = (header >> 8) & 0b111;
+    let data = header & 0xff;
+    if tag != 0b000 || data & 0xf0 != EXT_HEADER {
+        return 1;
+    }
+    ext_len((data & 0xf) as u8).map_or(1, |len| len + 1)
+}
+
+/// Deserialize an event from a frame, header first
+pub fn deserialize_frame(words: &[Message]) -> Result<(Event, Sid), Error> {
+    let (header, continuation) = words.split_first().ok_or(Error::Deserialization)?;
+    if continuation.is_empty() {
+        return deserialize(*header);
+    }
+    if frame_len(*header) != words.len() {
+        return Err(Error::Deserialization);
+    }
+    let header = checked(*header)?;
+    let sid = Sid::from_u32_lsb((header >> 11) as u32);
+    let kind = (header & 0xf) as u8;
+    let mut payload = [0; MAX_FRAME_LEN - 1];
+    for (i, word) in continuation.iter().enumerate() {
+        payload[i] = continuation_payload(*word, sid, i + 1)?;
+    }
+    Ok((Event::from_ext_payload(kind, &payload)?, sid))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::geometry::{CHARYBDIS_3X5, WIRE_COLS, WIRE_ROWS};
     use crate::log::*;
     use crate::rgb_anims::ERROR_COLOR_INDEX;
     use crate::sid::Sid;
+    use proptest::prelude::*;
+
+    /// Flipped bits, beyond what the forward error correction can fix
+    const CORRUPTION: u32 = 0x0001_0101;
 
-    const VALID_EVENTS: [(Event, Sid); 38] = [
+    const VALID_EVENTS: [(Event, Sid); 53] = [
         (Event::Noop, Sid::new(0x0)),
         (Event::Noop, Sid::new(0xa)),
         (Event::Noop, Sid::new(31)),
@@ -123,6 +513,9 @@ mod tests {
         (Event::Ack(Sid::new(0)), Sid::new(0)),
         (Event::Ack(Sid::new(13)), Sid::new(26)),
         (Event::Ack(Sid::new(17)), Sid::new(31)),
+        (Event::AckUpTo(Sid::new(0)), Sid::new(0)),
+        (Event::AckUpTo(Sid::new(13)), Sid::new(26)),
+        (Event::AckUpTo(Sid::new(31)), Sid::new(31)),
         (Event::Press(0, 1), Sid::new(1)),
         (Event::Press(1, 0), Sid::new(2)),
         (Event::Press(1, 9), Sid::new(24)),
@@ -158,6 +551,18 @@ mod tests {
         (Event::SeedRng(0), Sid::new(17)),
         (Event::SeedRng(8), Sid::new(19)),
         (Event::SeedRng(255), Sid::new(21)),
+        (Event::Hello(1, 0), Sid::new(4)),
+        (Event::Hello(6, 0xa), Sid::new(6)),
+        (Event::Hello(11, 0xf), Sid::new(31)),
+        (Event::SetLinkSpeed(0), Sid::new(6)),
+        (Event::SetLinkSpeed(3), Sid::new(14)),
+        (Event::SetLinkSpeed(15), Sid::new(30)),
+        (Event::Reset, Sid::new(0)),
+        (Event::Reset, Sid::new(19)),
+        (Event::Over, Sid::new(0)),
+        (Event::SensorCommand(1), Sid::new(8)),
+        (Event::SensorCommand(7), Sid::new(16)),
+        (Event::SensorCommand(SENSOR_COMMAND_MAX), Sid::new(31)),
     ];
 
     #[test]
@@ -172,13 +577,207 @@ mod tests {
         }
     }
 
+    /// Keys held on the right half
+    const RIGHT_KEYS: u32 = 0x10_8421;
+
+    const VALID_EXT_EVENTS: [(Event, Sid); 11] = [
+        (Event::SeedRng32(0), Sid::new(0)),
+        (Event::SeedRng32(1), Sid::new(5)),
+        (Event::SeedRng32(0xdead_beef), Sid::new(17)),
+        (Event::SeedRng32(u32::MAX), Sid::new(31)),
+        (Event::MouseMove(MouseMove { dx: 0, dy: 0 }), Sid::new(1)),
+        (Event::MouseMove(MouseMove { dx: -1, dy: 1 }), Sid::new(2)),
+        (
+            Event::MouseMove(MouseMove { dx: 300, dy: -42 }),
+            Sid::new(20),
+        ),
+        (
+            Event::MouseMove(MouseMove {
+                dx: i16::MIN,
+                dy: i16::MAX,
+            }),
+            Sid::new(30),
+        ),
+        (Event::KeySnapshot(KeyMatrix::new()), Sid::new(3)),
+        (
+            Event::KeySnapshot(KeyMatrix::from_u32(0xf_ffff)),
+            Sid::new(9),
+        ),
+        (
+            Event::KeySnapshot(KeyMatrix::from_u32(RIGHT_KEYS)),
+            Sid::new(27),
+        ),
+    ];
+
+    #[test]
+    fn test_frame_ser_de() {
+        for (event, sid) in VALID_EVENTS.iter().copied() {
+            let frame = serialize_frame(event, sid).unwrap();
+            assert_eq!(frame.words(), &[serialize(event, sid).unwrap()]);
+            assert_eq!(frame_len(frame.words()[0]), 1);
+            assert_eq!(deserialize_frame(frame.words()), Ok((event, sid)));
+        }
+        for (event, sid) in VALID_EXT_EVENTS.iter().copied() {
+            debug!("SER: Event: {:?}, sid: {}", event, sid);
+            assert_eq!(serialize(event, sid), Err(Error::Serialization));
+            let frame = serialize_frame(event, sid).unwrap();
+            assert_eq!(frame_len(frame.words()[0]), frame.words().len());
+            assert!(deserialize(frame.words()[0]).is_err());
+            let de = deserialize_frame(frame.words()).unwrap();
+            debug!("DE: Event: {:?}, sid: {}", de.0, de.1);
+            assert_eq!((event, sid), de);
+        }
+    }
+
+    #[test]
+    fn test_frame_bad_crc() {
+        for (event, sid) in VALID_EXT_EVENTS.iter().copied() {
+            let frame = serialize_frame(event, sid).unwrap();
+            for i in 0..frame.words().len() {
+                let mut words = frame.words;
+                words[i] ^= CORRUPTION;
+                let len = frame.words().len();
+                assert_eq!(
+                    Err(Error::Deserialization),
+                    deserialize_frame(&words[..len])
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_frame_mismatch() {
+        let frame = serialize_frame(Event::SeedRng32(42), Sid::new(3)).unwrap();
+        let words = frame.words();
+        // Truncated frame
+        assert!(deserialize_frame(&words[..2]).is_err());
+        // Continuation words of another frame
+        let other = serialize_frame(Event::SeedRng32(42), Sid::new(4)).unwrap();
+        assert!(deserialize_frame(&[words[0], other.words()[1], other.words()[2]]).is_err());
+        // Continuation words in the wrong order
+        assert!(deserialize_frame(&[words[0], words[2], words[1]]).is_err());
+    }
+
+    #[test]
+    fn test_hello_out_of_range() {
+        assert!(serialize(Event::Hello(0, 0), Sid::new(0)).is_err());
+        assert!(serialize(Event::Hello(12, 0), Sid::new(0)).is_err());
+        assert!(serialize(Event::Hello(1, 0x10), Sid::new(0)).is_err());
+        assert!(serialize(Event::SetLinkSpeed(0x10), Sid::new(0)).is_err());
+        assert!(serialize(Event::SensorCommand(0), Sid::new(0)).is_err());
+        assert!(serialize(Event::SensorCommand(0x10), Sid::new(0)).is_err());
+    }
+
+    #[test]
+    fn test_priority() {
+        assert_eq!(Event::Press(0, 1).priority(), Priority::Input);
+        assert_eq!(Event::Release(0, 1).priority(), Priority::Input);
+        assert_eq!(
+            Event::KeySnapshot(KeyMatrix::new()).priority(),
+            Priority::Input
+        );
+        assert_eq!(Event::SeedRng(3).priority(), Priority::State);
+        assert_eq!(Event::Hello(1, 2).priority(), Priority::State);
+        assert_eq!(Event::RgbAnimChangeLayer(2).priority(), Priority::Cosmetic);
+        assert!(Event::RgbAnimChangeLayer(2).supersedes(&Event::RgbAnimChangeLayer(1)));
+        assert!(Event::RgbAnim(RgbAnimType::Wheel).supersedes(&Event::RgbAnim(RgbAnimType::Off)));
+        assert!(!Event::RgbAnim(RgbAnimType::Wheel).supersedes(&Event::RgbAnimChangeLayer(1)));
+        assert!(!Event::Press(0, 1).supersedes(&Event::Press(0, 1)));
+    }
+
+    #[test]
+    fn test_key_snapshot_both_halves() {
+        let mut keys = KeyMatrix::new();
+        keys.set(0, 0, true);
+        keys.set(0, 9, true);
+        assert_eq!(
+            serialize_frame(Event::KeySnapshot(keys), Sid::new(0)).err(),
+            Some(Error::Serialization)
+        );
+    }
+
+    #[test]
+    fn test_ack_out_of_range() {
+        for data in [0x20, 0x7f, 0xa0, 0xff] {
+            let msg = with_check((0b010 << 8) | data);
+            assert_eq!(Err(Error::Deserialization), deserialize(msg));
+        }
+    }
+
+    #[test]
+    fn test_key_bounds() {
+        let (r, c) = (CHARYBDIS_3X5.rows as u8 - 1, CHARYBDIS_3X5.cols() as u8 - 1);
+        let data = key_data(&CHARYBDIS_3X5, r, c).unwrap();
+        assert_eq!(data_key(&CHARYBDIS_3X5, data as u8), Some((r, c)));
+        assert_eq!(key_data(&CHARYBDIS_3X5, r + 1, 0), None);
+        assert_eq!(key_data(&CHARYBDIS_3X5, 0, c + 1), None);
+        assert_eq!(data_key(&CHARYBDIS_3X5, (r + 1) << 4), None);
+        assert_eq!(data_key(&CHARYBDIS_3X5, c + 1), None);
+        // The wire format addresses up to 16x16 keys
+        let wire = Geometry {
+            rows: WIRE_ROWS,
+            half_cols: WIRE_COLS / 2,
+        };
+        assert_eq!(key_data(&wire, 15, 15), Some(0xff));
+        assert_eq!(data_key(&wire, 0xff), Some((15, 15)));
+
+        let (r, c) = (GEOMETRY.rows as u8 - 1, GEOMETRY.cols() as u8 - 1);
+        for event in [Event::Press(r, c), Event::Release(r, c)] {
+            let msg = serialize(event, Sid::new(5)).unwrap();
+            assert_eq!(deserialize(msg), Ok((event, Sid::new(5))));
+        }
+        assert_eq!(
+            serialize(Event::Press(r + 1, 0), Sid::new(5)),
+            Err(Error::Serialization)
+        );
+        assert_eq!(
+            serialize(Event::Release(0, c + 1), Sid::new(5)),
+            Err(Error::Serialization)
+        );
+    }
+
+    #[test]
+    fn test_key_out_of_range() {
+        let (rows, cols) = (GEOMETRY.rows as u16, GEOMETRY.cols() as u16);
+        for data in [cols, 0x0f, rows << 4, 0xf0 | (cols - 1)] {
+            for tag in [0b011, 0b100] {
+                let msg = with_check((tag << 8) | data);
+                assert_eq!(Err(Error::Deserialization), deserialize(msg));
+            }
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn prop_deserialize(word: u32) {
+            if let Ok((event, sid)) = deserialize(word) {
+                prop_assert_eq!(deserialize(serialize(event, sid).unwrap()), Ok((event, sid)));
+            }
+        }
+
+        #[test]
+        fn prop_deserialize_valid_check(ser: u16) {
+            // Every 16-bit payload goes through the decoder, not only the
+            // few words with a valid check among random ones
+            if let Ok((event, sid)) = deserialize(with_check(ser)) {
+                prop_assert_eq!(deserialize(serialize(event, sid).unwrap()), Ok((event, sid)));
+            }
+        }
+
+        #[test]
+        fn prop_deserialize_frame(words in prop::collection::vec(any::<u32>(), 0..=MAX_FRAME_LEN)) {
+            if let Ok((event, sid)) = deserialize_frame(&words) {
+                let frame = serialize_frame(event, sid).unwrap();
+                prop_assert_eq!(deserialize_frame(frame.words()), Ok((event, sid)));
+            }
+        }
+    }
+
     #[test]
     fn test_bad_crc() {
         for (event, sid) in VALID_EVENTS.iter().copied() {
             let ser = serialize(event, sid).unwrap();
-            let mut bytes = ser.to_le_bytes();
-            bytes[0] = bytes[0].wrapping_add(1);
-            let bad_crc = u32::from_le_bytes(bytes);
+            let bad_crc = ser ^ CORRUPTION;
             assert_eq!(Err(Error::Deserialization), deserialize(bad_crc));
         }
     }
@@ -24,6 +24,14 @@ impl Sid {
             self.v += 1;
         }
     }
+    /// Get the previous sequence id
+    pub fn prev(&mut self) {
+        if self.v == 0 {
+            self.v = SID_MAX_U8;
+        } else {
+            self.v -= 1;
+        }
+    }
     /// As usize
     pub fn as_usize(&self) -> usize {
         self.v as usize
@@ -39,6 +47,11 @@ impl Sid {
         Self { v: v as u8 }
     }
 
+    /// Number of steps to go from this sequence id to @other
+    pub fn distance(&self, other: Sid) -> usize {
+        (other.v + SID_MAX_U8 + 1 - self.v) as usize % (SID_MAX_U8 as usize + 1)
+    }
+
     /// Get the maximum sequence id
     pub const fn max() -> Self {
         Self { v: SID_MAX_U8 }
@@ -148,6 +161,11 @@ impl<T: Copy> CircBuf<T> {
         self.count == 0
     }
 
+    /// Number of elements
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
     /// Iterator on all the elements
     pub fn iter(&self) -> core::slice::Iter<Option<T>> {
         self.arr.iter()
@@ -163,6 +181,7 @@ impl<T: Copy> Default for CircBuf<T> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
 
     #[test]
     fn test_max() {
@@ -182,6 +201,27 @@ mod tests {
     }
 
     #[test]
+    fn test_prev() {
+        let mut sid = Sid::new(2);
+        sid.prev();
+        assert_eq!(sid, Sid::new(1));
+        sid.prev();
+        assert_eq!(sid, Sid::new(0));
+        sid.prev();
+        assert_eq!(sid, Sid::new(31));
+    }
+
+    #[test]
+    fn test_distance() {
+        assert_eq!(Sid::new(3).distance(Sid::new(3)), 0);
+        assert_eq!(Sid::new(3).distance(Sid::new(7)), 4);
+        assert_eq!(Sid::new(30).distance(Sid::new(2)), 4);
+        assert_eq!(Sid::new(0).distance(Sid::new(31)), 31);
+        assert_eq!(Sid::new(31).distance(Sid::new(0)), 1);
+    }
+
+    #[test]
+    #[allow(clippy::while_let_on_iterator)]
     fn test_sid_iter() {
         let sid = Sid::new(3);
         let mut iter = sid.iter(Sid::new(6));
@@ -213,6 +253,38 @@ mod tests {
         assert_eq!(count, 1);
     }
 
+    proptest! {
+        #[test]
+        fn prop_sid_iter(start in 0..=SID_MAX_U8, end in 0..=SID_MAX_U8) {
+            let (start, end) = (Sid::new(start), Sid::new(end));
+            let sids: Vec<Sid> = start.iter(end).collect();
+            // Starting at the end goes all the way around
+            let expected = match start.distance(end) {
+                0 => SID_MAX_U8 as usize + 1,
+                distance => distance,
+            };
+            prop_assert_eq!(sids.len(), expected);
+            prop_assert_eq!(sids[0], start);
+            for pair in sids.windows(2) {
+                let mut sid = pair[0];
+                sid.next();
+                prop_assert_eq!(sid, pair[1]);
+            }
+            let mut last = sids[sids.len() - 1];
+            last.next();
+            prop_assert_eq!(last, end);
+        }
+
+        #[test]
+        fn prop_next_prev(v in 0..=SID_MAX_U8) {
+            let mut sid = Sid::new(v);
+            sid.next();
+            prop_assert_eq!(Sid::new(v).distance(sid), 1);
+            sid.prev();
+            prop_assert_eq!(sid, Sid::new(v));
+        }
+    }
+
     #[test]
     fn test_circ_buf() {
         let mut buf = CircBuf::new();
@@ -227,6 +299,7 @@ mod tests {
         assert_eq!(buf.get(Sid::new(0)), Some(1));
         assert_eq!(buf.get(Sid::new(1)), Some(2));
         assert_eq!(buf.get(Sid::new(2)), Some(3));
+        assert_eq!(buf.len(), 3);
         assert_eq!(buf.take(Sid::new(24)), None);
         assert_eq!(buf.take(Sid::new(1)), Some(2));
         assert_eq!(buf.take(Sid::new(1)), None);
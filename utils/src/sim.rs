@@ -0,0 +1,636 @@
+//! Simulation of the link between the halves, over a lossy channel
+//!
+//! Two `SideProtocol` instances are connected through channels that corrupt,
+//! drop, duplicate and delay the words sent.  Everything is driven by a seeded
+//! PRNG, so that a failing session can be replayed from its seed.
+
+use crate::prng::XorShift32;
+use crate::protocol::{
+    HalfDuplex, Handshake, Hardware, LinkState, SideProtocol, CAP_CUMULATIVE_ACK,
+};
+use crate::serde::{Event, Message};
+use std::collections::VecDeque;
+
+/// Period at which the protocol timeouts are checked, in ms, as on the firmware
+const TICK_PERIOD_MS: u64 = 5;
+
+/// Build hash used by both sides for the handshake
+const BUILD_HASH: u32 = 0x5eed_cafe;
+
+/// Impairments of a channel
+/// Rates are probabilities, between 0 and 1
+#[derive(Debug, Clone, Copy)]
+pub struct ChannelConfig {
+    /// Probability of each bit to be flipped
+    pub bit_error_rate: f64,
+    /// Probability of a word to start a burst of corrupted words
+    pub burst_rate: f64,
+    /// Maximum number of consecutive words corrupted by a burst
+    pub burst_len: usize,
+    /// Probability of a word to be dropped
+    pub drop_rate: f64,
+    /// Probability of a word to be received twice
+    pub duplicate_rate: f64,
+    /// Time for a word to go through the channel, in ms
+    pub latency_ms: u64,
+    /// Whether both directions share a single wire, the right side leading
+    /// Words sent while the wire carries words the other way collide and are
+    /// lost.
+    pub half_duplex: bool,
+}
+
+impl Default for ChannelConfig {
+    /// A perfect channel
+    fn default() -> Self {
+        Self {
+            bit_error_rate: 0.0,
+            burst_rate: 0.0,
+            burst_len: 5,
+            drop_rate: 0.0,
+            duplicate_rate: 0.0,
+            latency_ms: 1,
+            half_duplex: false,
+        }
+    }
+}
+
+/// One direction of the link
+struct Channel {
+    /// Impairments of the channel
+    config: ChannelConfig,
+    /// PRNG driving the impairments
+    prng: XorShift32,
+    /// Number of words left to corrupt in the current burst
+    burst: usize,
+    /// Words in the channel, with the time they are received
+    in_flight: VecDeque<(u64, Message)>,
+}
+
+impl Channel {
+    /// Create a new channel
+    fn new(config: ChannelConfig, seed: u32) -> Self {
+        Self {
+            config,
+            prng: XorShift32::new(seed),
+            burst: 0,
+            in_flight: VecDeque::new(),
+        }
+    }
+
+    /// Whether an impairment of the given probability happens
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && (self.prng.random() as f64 / u32::MAX as f64) < probability
+    }
+
+    /// Send a word through the channel
+    fn send(&mut self, now: u64, msg: Message) {
+        if self.chance(self.config.drop_rate) {
+            return;
+        }
+        let mut msg = msg;
+        if self.burst == 0 && self.chance(self.config.burst_rate) {
+            self.burst = 1 + self.prng.random() as usize % self.config.burst_len.max(1);
+        }
+        if self.burst > 0 {
+            self.burst -= 1;
+            msg ^= self.prng.random() | 1;
+        }
+        for bit in 0..32 {
+            if self.chance(self.config.bit_error_rate) {
+                msg ^= 1 << bit;
+            }
+        }
+        let at = now + self.config.latency_ms;
+        self.in_flight.push_back((at, msg));
+        if self.chance(self.config.duplicate_rate) {
+            self.in_flight.push_back((at, msg));
+        }
+    }
+
+    /// Get the next word received, if any
+    fn receive(&mut self, now: u64) -> Option<Message> {
+        match self.in_flight.front() {
+            Some((at, _)) if *at <= now => self.in_flight.pop_front().map(|(_, msg)| msg),
+            _ => None,
+        }
+    }
+}
+
+/// Hardware of a simulated side
+#[derive(Debug, Default)]
+pub struct SimHardware {
+    /// Words sent, not yet in the channel
+    outbox: VecDeque<Message>,
+    /// Events processed, in order
+    events: Vec<Event>,
+    /// Simulated clock, in ms
+    now: u64,
+}
+
+impl SimHardware {
+    /// Events processed, in order
+    pub fn events(&self) -> &[Event] {
+        &self.events
+    }
+}
+
+impl Hardware for SimHardware {
+    async fn send(&mut self, msg: Message) {
+        self.outbox.push_back(msg);
+    }
+    async fn wait_a_bit(&mut self) {}
+    async fn process_event(&mut self, event: Event) {
+        self.events.push(event);
+    }
+    async fn set_error_state(&mut self, _error: bool) {}
+    fn now(&self) -> u64 {
+        self.now
+    }
+    async fn on_link_state(&mut self, _state: LinkState) {}
+    async fn on_handshake(&mut self, _handshake: Handshake) {}
+    async fn set_link_speed(&mut self, _speed: u32) {}
+    async fn on_peer_reset(&mut self) {}
+}
+
+/// Both sides and the channels between them
+pub struct Simulator {
+    /// Right side
+    pub right: SideProtocol<SimHardware>,
+    /// Left side
+    pub left: SideProtocol<SimHardware>,
+    /// Channel from the right side to the left one
+    to_left: Channel,
+    /// Channel from the left side to the right one
+    to_right: Channel,
+    /// Events waiting for room in the queue of the right side
+    right_pending: VecDeque<Event>,
+    /// Events waiting for room in the queue of the left side
+    left_pending: VecDeque<Event>,
+    /// Simulated clock, in ms
+    now: u64,
+}
+
+impl Simulator {
+    /// Create a new simulator, both directions having the same impairments
+    pub fn new(config: ChannelConfig, seed: u32) -> Self {
+        let mut right = SideProtocol::new(SimHardware::default(), "right");
+        let mut left = SideProtocol::new(SimHardware::default(), "left");
+        right.enable_handshake(BUILD_HASH);
+        left.enable_handshake(BUILD_HASH);
+        right.set_capabilities(CAP_CUMULATIVE_ACK);
+        left.set_capabilities(CAP_CUMULATIVE_ACK);
+        if config.half_duplex {
+            right.set_half_duplex(HalfDuplex::Leader);
+            left.set_half_duplex(HalfDuplex::Follower);
+        }
+        let mut prng = XorShift32::new(seed);
+        Self {
+            right,
+            left,
+            to_left: Channel::new(config, prng.random()),
+            to_right: Channel::new(config, prng.random()),
+            right_pending: VecDeque::new(),
+            left_pending: VecDeque::new(),
+            now: 0,
+        }
+    }
+
+    /// Simulated clock, in ms
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    /// Send an event from the right side, as soon as its queue has room
+    pub fn send_from_right(&mut self, event: Event) {
+        self.right_pending.push_back(event);
+    }
+
+    /// Send an event from the left side, as soon as its queue has room
+    pub fn send_from_left(&mut self, event: Event) {
+        self.left_pending.push_back(event);
+    }
+
+    /// Advance the simulation by 1ms
+    // `is_multiple_of()` is only stable since Rust 1.87, above the MSRV
+    #[allow(clippy::manual_is_multiple_of)]
+    pub async fn step(&mut self) {
+        self.now += 1;
+        let now = self.now;
+        self.right.hw_mut().now = now;
+        self.left.hw_mut().now = now;
+
+        while !self.right.is_queue_full() {
+            match self.right_pending.pop_front() {
+                Some(event) => self.right.queue_event(event).await.unwrap(),
+                None => break,
+            }
+        }
+        while !self.left.is_queue_full() {
+            match self.left_pending.pop_front() {
+                Some(event) => self.left.queue_event(event).await.unwrap(),
+                None => break,
+            }
+        }
+
+        if now % TICK_PERIOD_MS == 0 {
+            self.right.tick().await;
+            self.left.tick().await;
+        }
+
+        while let Some(msg) = self.to_left.receive(now) {
+            self.left.receive(msg).await;
+        }
+        while let Some(msg) = self.to_right.receive(now) {
+            self.right.receive(msg).await;
+        }
+
+        let right_talks = !self.right.hw().outbox.is_empty();
+        let left_talks = !self.left.hw().outbox.is_empty();
+        let collision = self.to_left.config.half_duplex
+            && (right_talks && (left_talks || !self.to_right.in_flight.is_empty())
+                || left_talks && !self.to_left.in_flight.is_empty());
+        while let Some(msg) = self.right.hw_mut().outbox.pop_front() {
+            if !collision {
+                self.to_left.send(now, msg);
+            }
+        }
+        while let Some(msg) = self.left.hw_mut().outbox.pop_front() {
+            if !collision {
+                self.to_right.send(now, msg);
+            }
+        }
+    }
+
+    /// Whether everything sent has been received and acknowledged
+    pub fn is_idle(&self) -> bool {
+        self.right_pending.is_empty()
+            && self.left_pending.is_empty()
+            && self.to_left.in_flight.is_empty()
+            && self.to_right.in_flight.is_empty()
+            && self.right.is_idle()
+            && self.left.is_idle()
+    }
+}
+
+/// Outcome of a simulated session
+#[derive(Debug)]
+pub struct Session {
+    /// Seed of the session
+    pub seed: u32,
+    /// Key events sent by the right side
+    pub right_sent: Vec<Event>,
+    /// Key events sent by the left side
+    pub left_sent: Vec<Event>,
+    /// Key events received by the right side
+    pub right_received: Vec<Event>,
+    /// Key events received by the left side
+    pub left_received: Vec<Event>,
+    /// Whether both sides were idle before the deadline
+    pub idle: bool,
+    /// Duration of the session, in ms
+    pub duration: u64,
+    /// Messages received, by both sides
+    pub received: u32,
+    /// Words that failed their check, each one asking for a retransmit, on
+    /// both sides
+    pub crc_errors: u32,
+}
+
+impl Session {
+    /// Whether each side received exactly the key events sent by the other
+    pub fn is_exact(&self) -> bool {
+        self.right_sent == self.left_received && self.left_sent == self.right_received
+    }
+}
+
+/// Keep only the key events
+fn key_events(events: &[Event]) -> Vec<Event> {
+    events
+        .iter()
+        .filter(|e| matches!(e, Event::Press(_, _) | Event::Release(_, _)))
+        .copied()
+        .collect()
+}
+
+/// Random key presses and releases of one side
+#[derive(Default)]
+struct Typist {
+    /// Keys currently pressed
+    pressed: Vec<(u8, u8)>,
+    /// Events generated
+    sent: Vec<Event>,
+}
+
+impl Typist {
+    /// Generate the next event, pressing or releasing a random key
+    /// Once `nb_events` are generated, the keys still pressed are released
+    fn next(&mut self, prng: &mut XorShift32, nb_events: usize) -> Option<Event> {
+        let r = prng.random();
+        let event = if self.sent.len() < nb_events {
+            let (i, j) = ((r % 4) as u8, ((r >> 8) % 10) as u8);
+            match self.pressed.iter().position(|k| *k == (i, j)) {
+                Some(idx) => {
+                    self.pressed.swap_remove(idx);
+                    Event::Release(i, j)
+                }
+                None => {
+                    self.pressed.push((i, j));
+                    Event::Press(i, j)
+                }
+            }
+        } else {
+            let (i, j) = self.pressed.pop()?;
+            Event::Release(i, j)
+        };
+        self.sent.push(event);
+        Some(event)
+    }
+
+    /// Whether all the events have been generated and all keys released
+    fn is_done(&self, nb_events: usize) -> bool {
+        self.sent.len() >= nb_events && self.pressed.is_empty()
+    }
+}
+
+/// Run a session where both sides type `nb_events` random key events, one
+/// every `period_ms` on average, then release the keys still pressed and wait
+/// up to `deadline_ms` for the link to settle
+// `is_multiple_of()` is only stable since Rust 1.87, above the MSRV
+#[allow(clippy::manual_is_multiple_of)]
+pub async fn run_session(
+    config: ChannelConfig,
+    seed: u32,
+    nb_events: usize,
+    period_ms: u64,
+    deadline_ms: u64,
+) -> Session {
+    let mut sim = Simulator::new(config, seed);
+    let mut prng = XorShift32::new(seed ^ 0xa5a5_a5a5);
+    let mut right = Typist::default();
+    let mut left = Typist::default();
+    let period = period_ms.max(1) as u32;
+
+    while !right.is_done(nb_events) || !left.is_done(nb_events) {
+        sim.step().await;
+        if prng.random() % period == 0 {
+            if let Some(event) = right.next(&mut prng, nb_events) {
+                sim.send_from_right(event);
+            }
+        }
+        if prng.random() % period == 0 {
+            if let Some(event) = left.next(&mut prng, nb_events) {
+                sim.send_from_left(event);
+            }
+        }
+    }
+
+    let deadline = sim.now() + deadline_ms;
+    while !sim.is_idle() && sim.now() < deadline {
+        sim.step().await;
+    }
+    Session {
+        seed,
+        right_received: key_events(sim.right.hw().events()),
+        left_received: key_events(sim.left.hw().events()),
+        right_sent: right.sent,
+        left_sent: left.sent,
+        idle: sim.is_idle(),
+        duration: sim.now(),
+        received: sim.right.stats().received + sim.left.stats().received,
+        crc_errors: sim.right.stats().crc_errors + sim.left.stats().crc_errors,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::WINDOW_SIZE;
+    use crate::serde::{deserialize, with_check};
+    use core::future;
+    use proptest::prelude::*;
+
+    /// Run many sessions over the given channel, checking each one
+    async fn run_sessions(config: ChannelConfig, nb_sessions: u32) -> Vec<Session> {
+        let mut sessions = Vec::new();
+        for seed in 1..=nb_sessions {
+            let session = run_session(config, seed, 40, 10, 60_000).await;
+            assert!(session.idle, "session {} did not settle", seed);
+            assert!(
+                session.is_exact(),
+                "session {} lost or reordered events: {:?}",
+                seed,
+                session
+            );
+            sessions.push(session);
+        }
+        sessions
+    }
+
+    #[tokio::test]
+    async fn test_perfect_channel() {
+        run_sessions(ChannelConfig::default(), 100).await;
+    }
+
+    #[tokio::test]
+    async fn test_bit_errors() {
+        let config = ChannelConfig {
+            bit_error_rate: 0.001,
+            ..ChannelConfig::default()
+        };
+        run_sessions(config, 1000).await;
+    }
+
+    #[tokio::test]
+    async fn test_bursts() {
+        let config = ChannelConfig {
+            burst_rate: 0.01,
+            burst_len: 5,
+            ..ChannelConfig::default()
+        };
+        #[cfg(not(feature = "fec-double"))]
+        run_sessions(config, 1000).await;
+        // Correcting 2 flipped bits, a garbled word is taken for a valid one
+        // about once in 124 times: events may be added or lost, but the link
+        // must settle
+        #[cfg(feature = "fec-double")]
+        for seed in 1..=1000 {
+            let session = run_session(config, seed, 40, 10, 60_000).await;
+            assert!(session.idle, "session {} did not settle", seed);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_drops() {
+        let config = ChannelConfig {
+            drop_rate: 0.02,
+            ..ChannelConfig::default()
+        };
+        run_sessions(config, 1000).await;
+    }
+
+    #[tokio::test]
+    async fn test_duplicates_and_latency() {
+        let config = ChannelConfig {
+            duplicate_rate: 0.02,
+            latency_ms: 3,
+            ..ChannelConfig::default()
+        };
+        run_sessions(config, 1000).await;
+    }
+
+    #[tokio::test]
+    async fn test_everything() {
+        let config = ChannelConfig {
+            bit_error_rate: 0.0005,
+            burst_rate: 0.005,
+            burst_len: 5,
+            drop_rate: 0.01,
+            duplicate_rate: 0.01,
+            latency_ms: 2,
+            half_duplex: false,
+        };
+        run_sessions(config, 1000).await;
+    }
+
+    #[tokio::test]
+    async fn test_half_duplex() {
+        let config = ChannelConfig {
+            bit_error_rate: 0.0005,
+            drop_rate: 0.01,
+            latency_ms: 2,
+            half_duplex: true,
+            ..ChannelConfig::default()
+        };
+        run_sessions(config, 1000).await;
+    }
+
+    #[cfg(feature = "fec")]
+    #[tokio::test]
+    async fn test_fec_bit_errors() {
+        let config = ChannelConfig {
+            bit_error_rate: 0.005,
+            ..ChannelConfig::default()
+        };
+        let sessions = run_sessions(config, 1000).await;
+        let received: u32 = sessions.iter().map(|s| s.received).sum();
+        let crc_errors: u32 = sessions.iter().map(|s| s.crc_errors).sum();
+        // About 15% of the words would fail the CRC16 and need a retransmit
+        #[cfg(not(feature = "fec-double"))]
+        assert!(crc_errors * 50 < received, "{}/{}", crc_errors, received);
+        #[cfg(feature = "fec-double")]
+        assert!(crc_errors * 500 < received, "{}/{}", crc_errors, received);
+    }
+
+    /// Run a future to completion from a property test
+    fn block_on<F: future::Future>(f: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(f)
+    }
+
+    /// Inject words on the link of a session where the right side types
+    /// @keys, then check that it settles once the injection stops
+    async fn run_noise(
+        noise: &[(bool, Message, u64)],
+        keys: &[(u8, u8)],
+    ) -> Result<(), TestCaseError> {
+        let mut sim = Simulator::new(ChannelConfig::default(), 1);
+        // Bring the link up, as the first ping of the firmware
+        sim.send_from_right(Event::Ping);
+        while !sim.is_idle() || sim.left.link_state() != LinkState::Up {
+            sim.step().await;
+        }
+        let mut sent = Vec::new();
+        let mut keys = keys.iter();
+        // A valid message cannot be told from one of the other side: it may
+        // add, drop or reorder key events, but must not break the link
+        let mut forged = false;
+        for (to_left, msg, delay) in noise {
+            forged |= deserialize(*msg).is_ok();
+            if let Some((i, j)) = keys.next() {
+                sent.extend([Event::Press(*i, *j), Event::Release(*i, *j)]);
+                sim.send_from_right(Event::Press(*i, *j));
+                sim.send_from_right(Event::Release(*i, *j));
+            }
+            if *to_left {
+                sim.left.receive(*msg).await;
+            } else {
+                sim.right.receive(*msg).await;
+            }
+            for _ in 0..*delay {
+                sim.step().await;
+                prop_assert!(sim.left.window_stats().gaps <= WINDOW_SIZE);
+                prop_assert!(sim.right.window_stats().gaps <= WINDOW_SIZE);
+            }
+        }
+        for (i, j) in keys {
+            sent.extend([Event::Press(*i, *j), Event::Release(*i, *j)]);
+            sim.send_from_right(Event::Press(*i, *j));
+            sim.send_from_right(Event::Release(*i, *j));
+        }
+        let deadline = sim.now() + 60_000;
+        while !sim.is_idle() && sim.now() < deadline {
+            sim.step().await;
+        }
+        prop_assert!(sim.is_idle(), "did not settle");
+        if !forged {
+            prop_assert_eq!(key_events(sim.left.hw().events()), sent);
+        }
+        // An idle link is degraded until the next ping, and a ping that had
+        // to be sent again measures a slow round trip: the one after clears it
+        for _ in 0..2 {
+            sim.send_from_left(Event::Ping);
+            sim.send_from_right(Event::Ping);
+            let deadline = sim.now() + 1000;
+            while !sim.is_idle() && sim.now() < deadline {
+                sim.step().await;
+            }
+        }
+        prop_assert_eq!(sim.left.link_state(), LinkState::Up);
+        prop_assert_eq!(sim.right.link_state(), LinkState::Up);
+        Ok(())
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn prop_noise(
+            noise in prop::collection::vec(
+                (
+                    any::<bool>(),
+                    // Random words, and valid ones with random payloads
+                    prop_oneof![any::<u32>(), any::<u16>().prop_map(with_check)],
+                    0..20u64,
+                ),
+                0..200,
+            ),
+            keys in prop::collection::vec((0..4u8, 0..10u8), 0..20),
+        ) {
+            block_on(run_noise(&noise, &keys))?;
+        }
+
+        #[test]
+        fn prop_channel(
+            seed: u32,
+            bit_error_rate in 0.0..0.002,
+            drop_rate in 0.0..0.05,
+            duplicate_rate in 0.0..0.05,
+            latency_ms in 1..5u64,
+            half_duplex: bool,
+        ) {
+            let config = ChannelConfig {
+                bit_error_rate,
+                drop_rate,
+                duplicate_rate,
+                latency_ms,
+                half_duplex,
+                ..ChannelConfig::default()
+            };
+            let session = block_on(run_session(config, seed, 40, 10, 60_000));
+            prop_assert!(session.idle, "session {} did not settle", seed);
+            prop_assert!(session.is_exact(), "session {} lost or reordered events", seed);
+        }
+    }
+}